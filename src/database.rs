@@ -0,0 +1,323 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use json::{self, JsonValue};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+
+/// The recorded state of a single output file from the last time it was built.
+#[derive(Clone, Default)]
+pub struct Entry {
+    /// Content digests of each input file at the time the output was built.
+    pub inputs: HashMap<String, String>,
+
+    /// Content digest of the output file after it was built.
+    pub output: Option<String>,
+
+    /// Fingerprint of the action that built the output.
+    pub action: Option<String>,
+
+    /// Command lines executed by the action when building the output.
+    pub commands: Vec<CommandLine>,
+}
+
+/// A command line executed by an action.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CommandLine {
+    /// The arguments as they were given, before variables were expanded.
+    pub template: Vec<String>,
+
+    /// The arguments the command was actually run with.
+    pub args: Vec<String>,
+}
+
+/// A persistent database of build state.
+///
+/// The database is stored as a JSON file, usually inside the `.rote` directory next to the
+/// Rotefile. Changes are only kept in memory until `save()` is called.
+pub struct Database {
+    /// Path to the database file.
+    path: PathBuf,
+
+    /// Entries for each output file, keyed by the file name.
+    entries: HashMap<String, Entry>,
+
+    /// How long each task took to run the last time it was run, in milliseconds.
+    durations: HashMap<String, u64>,
+
+    /// Indicates if the database has changed since it was loaded.
+    dirty: bool,
+}
+
+impl Database {
+    /// Creates a new, empty database that will be stored at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Database {
+        Database {
+            path: path.into(),
+            entries: HashMap::new(),
+            durations: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Opens the database stored at the given path.
+    ///
+    /// If the file does not exist yet, an empty database is returned.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Database, Box<Error>> {
+        let mut database = Database::new(path);
+
+        if fs::metadata(&database.path).is_err() {
+            return Ok(database);
+        }
+
+        let mut source = String::new();
+        try!(try!(File::open(&database.path)).read_to_string(&mut source));
+        let value = try!(json::parse(&source));
+
+        for (output, record) in value["outputs"].entries() {
+            let mut entry = Entry::default();
+
+            for (input, digest) in record["inputs"].entries() {
+                if let Some(digest) = digest.as_str() {
+                    entry.inputs.insert(input.to_string(), digest.to_string());
+                }
+            }
+
+            for command in record["commands"].members() {
+                entry.commands.push(CommandLine {
+                    template: strings(&command["template"]),
+                    args: strings(&command["args"]),
+                });
+            }
+
+            entry.output = record["output"].as_str().map(|s| s.to_string());
+            entry.action = record["action"].as_str().map(|s| s.to_string());
+            database.entries.insert(output.to_string(), entry);
+        }
+
+        for (task, duration) in value["durations"].entries() {
+            if let Some(duration) = duration.as_f64() {
+                database.durations.insert(task.to_string(), duration as u64);
+            }
+        }
+
+        Ok(database)
+    }
+
+    /// Gets the recorded entry for an output file.
+    pub fn get<S: AsRef<str>>(&self, output: S) -> Option<&Entry> {
+        self.entries.get(output.as_ref())
+    }
+
+    /// Records the state of an output file, replacing any previous entry.
+    pub fn insert<S: Into<String>>(&mut self, output: S, entry: Entry) {
+        self.entries.insert(output.into(), entry);
+        self.dirty = true;
+    }
+
+    /// Gets how long a task took to run the last time it was run.
+    pub fn duration<S: AsRef<str>>(&self, task: S) -> Option<Duration> {
+        self.durations.get(task.as_ref()).map(|&ms| Duration::from_millis(ms))
+    }
+
+    /// Records how long a task took to run.
+    pub fn set_duration<S: Into<String>>(&mut self, task: S, duration: Duration) {
+        let ms = duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000;
+        self.durations.insert(task.into(), ms);
+        self.dirty = true;
+    }
+
+    /// Writes the database to disk if it has been modified.
+    pub fn save(&mut self) -> Result<(), Box<Error>> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut outputs = JsonValue::new_object();
+
+        for (output, entry) in &self.entries {
+            let mut inputs = JsonValue::new_object();
+            for (input, digest) in &entry.inputs {
+                inputs[input.as_str()] = digest.as_str().into();
+            }
+
+            let mut commands = JsonValue::new_array();
+            for command in &entry.commands {
+                let mut record = JsonValue::new_object();
+                record["template"] = command.template.clone().into();
+                record["args"] = command.args.clone().into();
+                try!(commands.push(record));
+            }
+
+            let mut record = JsonValue::new_object();
+            record["inputs"] = inputs;
+            record["commands"] = commands;
+            if let Some(ref digest) = entry.output {
+                record["output"] = digest.as_str().into();
+            }
+            if let Some(ref fingerprint) = entry.action {
+                record["action"] = fingerprint.as_str().into();
+            }
+
+            outputs[output.as_str()] = record;
+        }
+
+        let mut durations = JsonValue::new_object();
+        for (task, &duration) in &self.durations {
+            durations[task.as_str()] = (duration as f64).into();
+        }
+
+        let mut value = JsonValue::new_object();
+        value["outputs"] = outputs;
+        value["durations"] = durations;
+
+        // Make sure the containing directory exists first.
+        if let Some(directory) = self.path.parent() {
+            try!(fs::create_dir_all(directory));
+        }
+
+        // Write to a temporary file first and move it into place, so that being interrupted while
+        // writing never leaves a partly written database behind.
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+
+        {
+            let mut file = try!(File::create(&temp));
+            try!(file.write_all(json::stringify(value).as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&temp, &self.path));
+
+        trace!("saved build database to '{}'", self.path.to_string_lossy());
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// Reads an array of strings from a JSON value, skipping anything that is not a string.
+fn strings(value: &JsonValue) -> Vec<String> {
+    value.members()
+        .filter_map(|member| member.as_str())
+        .map(|member| member.to_string())
+        .collect()
+}
+
+/// Computes the SHA-256 digest of a file's contents as a hex string.
+///
+/// Returns `None` if the file cannot be read.
+pub fn digest_file<P: AsRef<Path>>(path: P) -> Option<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => hasher.input(&buffer[..count]),
+            Err(_) => return None,
+        }
+    }
+
+    Some(hasher.result_str())
+}
+
+/// Computes the SHA-256 digest of a string as a hex string.
+pub fn digest_str<S: AsRef<str>>(string: S) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(string.as_ref());
+    hasher.result_str()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use super::*;
+
+    /// Gets a path in the temporary directory that is unique to the test.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rote-test-database-{}", name))
+    }
+
+    #[test]
+    fn missing_database_is_empty() {
+        let database = Database::open(temp_path("missing.json")).unwrap();
+
+        assert!(database.get("out").is_none());
+        assert!(database.duration("task").is_none());
+    }
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let path = temp_path("db").join("state.json");
+
+        let mut entry = Entry::default();
+        entry.inputs.insert("in.c".to_string(), "abc".to_string());
+        entry.output = Some("def".to_string());
+        entry.action = Some("123".to_string());
+        entry.commands.push(CommandLine {
+            template: vec!["cc".to_string(), "$CFLAGS".to_string()],
+            args: vec!["cc".to_string(), "-O2".to_string()],
+        });
+
+        let mut database = Database::new(&path);
+        database.insert("out.o", entry);
+        database.insert("bare.o", Entry::default());
+        database.set_duration("build", Duration::from_millis(1500));
+        database.save().unwrap();
+
+        let database = Database::open(&path).unwrap();
+        assert!(fs::metadata(path.with_extension("json.tmp")).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let entry = database.get("out.o").unwrap();
+        assert_eq!(entry.inputs["in.c"], "abc");
+        assert_eq!(entry.output, Some("def".to_string()));
+        assert_eq!(entry.action, Some("123".to_string()));
+        assert_eq!(entry.commands[0].template, vec!["cc", "$CFLAGS"]);
+        assert_eq!(entry.commands[0].args, vec!["cc", "-O2"]);
+
+        let bare = database.get("bare.o").unwrap();
+        assert!(bare.inputs.is_empty());
+        assert_eq!(bare.output, None);
+        assert_eq!(bare.action, None);
+        assert!(bare.commands.is_empty());
+
+        assert_eq!(database.duration("build"), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn unchanged_database_is_not_written() {
+        let path = temp_path("unchanged.json");
+
+        Database::new(&path).save().unwrap();
+        assert!(fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn digests_files() {
+        let path = temp_path("digest.txt");
+        File::create(&path).unwrap().write_all(b"hello").unwrap();
+
+        let digest = digest_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(digest, Some(digest_str("hello")));
+        assert_eq!(digest_str("hello"),
+                   "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(digest_file(&path), None);
+    }
+}
@@ -0,0 +1,352 @@
+use json::JsonValue;
+use lua;
+use output::Buffer;
+use processes::{self, Deadline, Processes};
+use reaper::Reaper;
+use runtime::{self, Context, Notify, Outcome, Reply, Runtime};
+use std::collections::HashMap;
+use std::error::Error;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use task::{Task, CONSOLE_POOL};
+use timer::Timer;
+use trace::Trace;
+
+
+/// A task that finished running, along with the slot it ran in.
+pub struct Finished {
+    pub slot: usize,
+    pub task: String,
+    pub duration: Duration,
+    pub attempts: u32,
+    pub result: Result<(), String>,
+}
+
+/// Runs tasks as Lua coroutines in a single runtime, instead of one runtime per thread.
+///
+/// Each task runs in one of a fixed number of slots until its function calls `exec` or `pipe`.
+/// The command is then waited on in the background while the task is suspended, so that other
+/// tasks can run in the meantime. Once the command exits, its reply is passed to `resume()` and
+/// the task picks up where it left off. A single reaper thread waits for every command, and the
+/// shared timer measures both timeouts and retry delays, so no thread is needed per task.
+///
+/// Since every task runs in the same Lua state, globals set by one task are visible to the others.
+pub struct Executor {
+    runtime: Runtime,
+    processes: Arc<Processes>,
+    timer: Arc<Timer>,
+
+    /// Receives the replies of operations, including retry delays.
+    notify: Notify,
+
+    /// How long tasks without a timeout of their own are allowed to run.
+    timeout: Option<Duration>,
+
+    /// Timeline of each slot, if the build is being recorded.
+    traces: Vec<Option<Trace>>,
+
+    /// Tasks that are waiting, keyed by the ID of the operation they wait for.
+    waiting: HashMap<usize, Waiting>,
+}
+
+/// An attempt at running a task.
+struct Attempt {
+    slot: usize,
+    task: Rc<Task>,
+
+    /// When the first attempt was started.
+    start: Instant,
+
+    /// Number of the attempt, starting at 1.
+    number: u32,
+
+    /// Stops the attempt if the task has a timeout.
+    deadline: Option<Deadline>,
+
+    /// Output collected for the task in grouped mode, across all attempts.
+    output: Buffer,
+}
+
+/// A task whose function is running as a coroutine.
+struct Job {
+    attempt: Attempt,
+
+    /// The coroutine running the task function.
+    thread: lua::State,
+
+    /// Reference that keeps the coroutine from being collected.
+    reference: lua::Reference,
+
+    /// The parts of the environment that belong to the task, swapped in while it runs.
+    context: Context,
+}
+
+/// What a task is waiting for.
+enum Waiting {
+    /// A command run by the task to finish.
+    Operation(Job),
+
+    /// The delay before running a failed task again to pass.
+    Retry(Attempt),
+}
+
+impl Executor {
+    /// Creates an executor that runs up to `slots` tasks at once in the given runtime.
+    ///
+    /// The replies of the operations that tasks wait for are sent to `notify`, and must be passed
+    /// back to `resume()`.
+    pub fn new(runtime: Runtime,
+               notify: Notify,
+               slots: usize,
+               timeout: Option<Duration>,
+               timer: Arc<Timer>,
+               trace: Option<Trace>)
+               -> Result<Executor, Box<Error>> {
+        let processes = runtime.environment().processes();
+        let reaper = try!(Reaper::new(processes.clone()));
+        runtime.environment().coroutines().set_notify(notify.clone(), reaper);
+
+        // Slots take the place of worker threads in the timeline.
+        let traces = (0..slots)
+            .map(|slot| trace.as_ref().map(|trace| trace.for_thread(slot + 1)))
+            .collect();
+
+        Ok(Executor {
+            processes: processes,
+            runtime: runtime,
+            timeout: timeout,
+            timer: timer,
+            notify: notify,
+            traces: traces,
+            waiting: HashMap::new(),
+        })
+    }
+
+    /// Starts running a task in a slot. Returns the outcome if the task finished without waiting.
+    pub fn start(&mut self, slot: usize, task: Rc<Task>) -> Option<Finished> {
+        self.attempt(Attempt {
+            slot: slot,
+            task: task,
+            start: Instant::now(),
+            number: 1,
+            deadline: None,
+            output: Buffer::default(),
+        })
+    }
+
+    /// Passes the reply of an operation to the task waiting for it. Returns the outcome if the
+    /// task finished.
+    pub fn resume(&mut self, operation: usize, reply: Reply) -> Option<Finished> {
+        match self.waiting.remove(&operation) {
+            Some(Waiting::Operation(mut job)) => {
+                // The coroutine expects `true` followed by the results, or `false` and an error.
+                let nargs = match reply {
+                    Ok(values) => {
+                        job.thread.push_bool(true);
+                        for value in &values {
+                            runtime::push_value(&mut job.thread, value);
+                        }
+                        values.len() as i32 + 1
+                    }
+                    Err(e) => {
+                        job.thread.push_bool(false);
+                        job.thread.push_string(&e);
+                        2
+                    }
+                };
+
+                self.run(job, nargs)
+            }
+            Some(Waiting::Retry(attempt)) => {
+                if processes::interrupted() {
+                    return self.finish(attempt, Err("interrupted".to_string()));
+                }
+
+                info!("retrying task '{}' (attempt {} of {})",
+                      attempt.task.name(),
+                      attempt.number,
+                      attempt.task.retries() + 1);
+                self.attempt(attempt)
+            }
+            None => None,
+        }
+    }
+
+    /// Starts an attempt at running a task.
+    fn attempt(&mut self, mut attempt: Attempt) -> Option<Finished> {
+        let runtime = self.runtime.clone();
+        let environment = runtime.environment();
+        let name = attempt.task.name().to_string();
+
+        // The processes of a previous attempt may have been killed.
+        self.processes.resume(&name);
+
+        attempt.deadline = attempt.task
+            .timeout()
+            .or(self.timeout)
+            .map(|timeout| Deadline::start(&name, timeout, &self.processes, &self.timer));
+
+        // Tasks in the console pool have the terminal to themselves.
+        self.processes.set_foreground(&name, attempt.task.pool() == Some(CONSOLE_POOL));
+
+        // Have the function of the task and its arguments pushed onto the stack.
+        let outer = environment.swap_context(Context {
+            output: attempt.output.clone(),
+            ..Context::default()
+        });
+        let started = attempt.task.start();
+        let context = environment.swap_context(outer);
+
+        let nargs = match started {
+            Ok(Some(call)) => call.nargs,
+            Ok(None) => {
+                // Tasks without a function to call, such as source files, run in place.
+                let outer = environment.swap_context(context);
+                let result = attempt.task.run();
+                environment.swap_context(outer);
+
+                return self.finish(attempt, result.map_err(|e| e.to_string()));
+            }
+            Err(e) => return self.finish(attempt, Err(e.to_string())),
+        };
+
+        // Move the function and its arguments into a new coroutine.
+        let mut state = runtime.state();
+        let mut thread = state.new_thread();
+        let reference = state.reference(lua::REGISTRYINDEX);
+        state.xmove(&mut thread, nargs + 1);
+
+        // A task that times out while busy in its own code is stopped inside its coroutine.
+        if let Some(ref deadline) = attempt.deadline {
+            deadline.set_interrupt(Some(runtime::interrupt(&thread)));
+        }
+
+        self.run(Job {
+                     attempt: attempt,
+                     thread: thread,
+                     reference: reference,
+                     context: context,
+                 },
+                 nargs)
+    }
+
+    /// Runs the coroutine of a task until it finishes or waits for an operation.
+    fn run(&mut self, mut job: Job, nargs: i32) -> Option<Finished> {
+        let runtime = self.runtime.clone();
+        let environment = runtime.environment();
+        let coroutines = environment.coroutines();
+
+        let outer = environment.swap_context(mem::replace(&mut job.context, Context::default()));
+        let outer_trace = environment.trace();
+        environment.set_trace(self.traces[job.attempt.slot].clone());
+        coroutines.set_current(job.thread.as_ptr());
+
+        let status = job.thread.resume(None, nargs);
+        let operation = coroutines.take_deferred();
+
+        coroutines.set_current(ptr::null_mut());
+        environment.set_trace(outer_trace);
+        job.context = environment.swap_context(outer);
+
+        let outcome = match status {
+            lua::ThreadStatus::Yield => {
+                match operation {
+                    Some(operation) => {
+                        self.waiting.insert(operation, Waiting::Operation(job));
+                        return None;
+                    }
+                    None => Err("task functions may not yield when run as coroutines".to_string()),
+                }
+            }
+            lua::ThreadStatus::Ok => Ok(()),
+            _ => Err(job.thread.to_str(-1).unwrap_or("unknown error").to_string()),
+        };
+
+        self.complete(job, outcome)
+    }
+
+    /// Finishes running a task whose coroutine is done.
+    fn complete(&mut self, job: Job, outcome: Outcome) -> Option<Finished> {
+        let runtime = self.runtime.clone();
+        let environment = runtime.environment();
+
+        // The coroutine may be collected once it is no longer referenced.
+        if let Some(ref deadline) = job.attempt.deadline {
+            deadline.set_interrupt(None);
+        }
+        runtime.state().unreference(lua::REGISTRYINDEX, job.reference);
+
+        // Let the task finish up, such as rules recording their outputs in the build database.
+        let outer = environment.swap_context(job.context);
+        let result = job.attempt.task.complete(outcome.map_err(|e| e.into()));
+        environment.swap_context(outer);
+
+        self.finish(job.attempt, result.map_err(|e| e.to_string()))
+    }
+
+    /// Finishes an attempt at running a task, scheduling another attempt if it failed and the
+    /// task allows it.
+    fn finish(&mut self, attempt: Attempt, result: Result<(), String>) -> Option<Finished> {
+        let Attempt { slot, task, start, number, deadline, output } = attempt;
+        let environment = self.runtime.environment();
+
+        let result = match deadline {
+            Some(deadline) => deadline.finish().and(result),
+            None => result,
+        };
+
+        let attempts = task.retries() + 1;
+        if let Err(ref e) = result {
+            if number < attempts && !processes::interrupted() {
+                warn!("task '{}' failed on attempt {} of {}: {}",
+                      task.name(),
+                      number,
+                      attempts,
+                      e);
+
+                // Wait for the retry delay on the timer, like any other operation.
+                let operation = environment.coroutines().new_operation();
+                let notify = self.notify.clone();
+                self.timer.schedule(task.retry_delay(),
+                                    move || notify(operation, Ok(Vec::new())));
+
+                self.waiting.insert(operation,
+                                    Waiting::Retry(Attempt {
+                                        slot: slot,
+                                        task: task,
+                                        start: start,
+                                        number: number + 1,
+                                        deadline: None,
+                                        output: output,
+                                    }));
+                return None;
+            }
+        }
+
+        let outer = environment.output().swap_buffer(output);
+        environment.output().flush(task.name());
+        environment.output().swap_buffer(outer);
+
+        if let Some(ref trace) = self.traces[slot] {
+            let mut args = JsonValue::new_object();
+            args["attempts"] = (number as f64).into();
+            match result {
+                Ok(()) => args["result"] = "ok".into(),
+                Err(ref e) => args["result"] = e.as_str().into(),
+            }
+
+            trace.span(task.name(), "task", start, Instant::now(), args);
+        }
+
+        Some(Finished {
+            slot: slot,
+            task: task.name().to_string(),
+            duration: start.elapsed(),
+            attempts: number,
+            result: result,
+        })
+    }
+}
@@ -1,9 +1,33 @@
+use json::JsonValue;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::rc::Rc;
-use task::Task;
+use std::str::FromStr;
+use task::{Kind, Status, Task};
 
 
+/// Formats that a graph can be exported in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// The Graphviz DOT language.
+    Dot,
+
+    /// A JSON object listing nodes and edges.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown graph format '{}'", s)),
+        }
+    }
+}
+
 /// Stores a directional, sparse graph of tasks and their dependencies.
 pub struct Graph {
     tasks: HashMap<String, Rc<Task>>,
@@ -30,18 +54,153 @@ impl Graph {
         }
     }
 
+    /// Gets every task in the graph, sorted by name.
+    pub fn tasks(&self) -> Vec<Rc<Task>> {
+        let mut tasks: Vec<Rc<Task>> = self.tasks
+            .values()
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        tasks.sort();
+
+        tasks
+    }
+
     /// Adds a task to the graph.
+    ///
+    /// The task is added under its name and under the name of each of its outputs.
     pub fn insert(&mut self, rule: Rc<Task>) {
+        for output in rule.outputs() {
+            self.tasks.insert(output.clone(), rule.clone());
+        }
+
         self.tasks.insert(rule.name().into(), rule);
     }
 
     /// Produces a queue of tasks to run in order to satisfy all task dependencies.
     ///
     /// Dependency solving is done by performing a topological sort of the entire graph using a
-    /// depth-first search-based algorithm.
-    pub fn solve(&self, skip_satisfied_tasks: bool) -> Result<VecDeque<Rc<Task>>, Box<Error>> {
-        Solver::new(&self, skip_satisfied_tasks).solve()
+    /// depth-first search-based algorithm. If `explain` is set, the reason why each scheduled task
+    /// has to run is printed.
+    pub fn solve(&self,
+                 skip_satisfied_tasks: bool,
+                 explain: bool)
+                 -> Result<VecDeque<Rc<Task>>, Box<Error>> {
+        Solver::new(&self, skip_satisfied_tasks, explain).solve()
+    }
+
+    /// Determines recursively if a task is satisfied, and if not, why it has to run. For a task to
+    /// be satisfied, its dependencies must also be satisfied.
+    pub fn status(&self, task: &Task) -> Result<Status, Box<Error>> {
+        let status = task.status();
+        if !status.is_satisfied() {
+            return Ok(status);
+        }
+
+        for dependency in task.dependencies() {
+            let dependency = try!(self.get(dependency));
+
+            if !try!(self.status(&*dependency)).is_satisfied() {
+                let reason = format!("dependency '{}' will run", dependency.name());
+                return Ok(Status::Outdated(reason));
+            }
+        }
+
+        Ok(Status::Satisfied)
+    }
+
+    /// Determines recursively if a task is satisfied.
+    pub fn satisfied(&self, task: &Task) -> Result<bool, Box<Error>> {
+        self.status(task).map(|status| status.is_satisfied())
+    }
+
+    /// Gets the names of the tasks that a task depends on.
+    ///
+    /// Dependencies can refer to a task by any of its outputs, so this may differ from the task's
+    /// list of dependencies.
+    pub fn dependencies(&self, task: &Task) -> Result<Vec<String>, Box<Error>> {
+        let mut names = Vec::new();
+
+        for dependency in task.dependencies() {
+            let name = try!(self.get(dependency)).name().to_string();
+
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Exports the graph in the Graphviz DOT language.
+    ///
+    /// Named tasks are drawn as boxes, file tasks as ellipses, and source files as notes. Tasks
+    /// that are already satisfied are grayed out.
+    pub fn to_dot(&self) -> Result<String, Box<Error>> {
+        let mut dot = String::from("digraph rote {\n");
+
+        for task in self.tasks() {
+            let shape = match task.kind() {
+                Kind::Named => "box",
+                Kind::File => "ellipse",
+                Kind::Source => "note",
+            };
+
+            dot.push_str(&format!("    {} [shape={}", quote(task.name()), shape));
+            if try!(self.satisfied(&*task)) {
+                dot.push_str(", color=gray, fontcolor=gray");
+            }
+            dot.push_str("];\n");
+        }
+
+        for task in self.tasks() {
+            for dependency in try!(self.dependencies(&*task)) {
+                dot.push_str(&format!("    {} -> {};\n", quote(task.name()), quote(&dependency)));
+            }
+        }
+
+        dot.push_str("}\n");
+        Ok(dot)
     }
+
+    /// Exports the graph as a JSON object with a list of nodes and a list of edges.
+    pub fn to_json(&self) -> Result<JsonValue, Box<Error>> {
+        let mut nodes = JsonValue::new_array();
+        let mut edges = JsonValue::new_array();
+
+        for task in self.tasks() {
+            let mut outputs = JsonValue::new_array();
+            for output in task.outputs() {
+                try!(outputs.push(output.as_str()));
+            }
+
+            let mut node = JsonValue::new_object();
+            node["name"] = task.name().into();
+            node["kind"] = task.kind().name().into();
+            node["satisfied"] = try!(self.satisfied(&*task)).into();
+            node["outputs"] = outputs;
+            try!(nodes.push(node));
+
+            for dependency in try!(self.dependencies(&*task)) {
+                let mut edge = JsonValue::new_object();
+                edge["from"] = task.name().into();
+                edge["to"] = dependency.into();
+                try!(edges.push(edge));
+            }
+        }
+
+        let mut value = JsonValue::new_object();
+        value["nodes"] = nodes;
+        value["edges"] = edges;
+
+        Ok(value)
+    }
+}
+
+/// Quotes a string as an ID in the DOT language.
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace("\\", "\\\\").replace("\"", "\\\""))
 }
 
 struct Solver<'a> {
@@ -54,16 +213,19 @@ struct Solver<'a> {
     schedule: VecDeque<Rc<Task>>,
     // Skip satisfied tasks?
     skip_satisfied_tasks: bool,
+    // Print why each scheduled task has to run?
+    explain: bool,
 }
 
 impl<'a> Solver<'a> {
-    fn new<'b>(graph: &'b Graph, skip_satisfied_tasks: bool) -> Solver<'b> {
+    fn new<'b>(graph: &'b Graph, skip_satisfied_tasks: bool, explain: bool) -> Solver<'b> {
         Solver {
             graph: graph,
             resolved: HashSet::new(),
             unresolved: HashSet::new(),
             schedule: VecDeque::new(),
             skip_satisfied_tasks: skip_satisfied_tasks,
+            explain: explain,
         }
     }
 
@@ -83,11 +245,20 @@ impl<'a> Solver<'a> {
     fn resolve(&mut self, task: Rc<Task>) -> Result<(), Box<Error>> {
         // First, check if the task is already satisfied. If it is, it and its dependencies do not
         // need to run and we can skip this task in the schedule.
-        if self.skip_satisfied_tasks && try!(self.satisfied(task.clone())) {
-            info!("task '{}' is up to date", task.name());
-            self.resolved.insert(task.clone());
-            return Ok(());
-        }
+        let status = if self.skip_satisfied_tasks {
+            try!(self.graph.status(&*task))
+        } else {
+            Status::Outdated("all tasks are being run".to_string())
+        };
+
+        let reason = match status {
+            Status::Satisfied => {
+                info!("task '{}' is up to date", task.name());
+                self.resolved.insert(task.clone());
+                return Ok(());
+            }
+            Status::Outdated(reason) => reason,
+        };
 
         // Mark this task as unresolved.
         self.unresolved.insert(task.clone());
@@ -110,28 +281,100 @@ impl<'a> Solver<'a> {
 
         // The task is now resolved.
         trace!("task '{}' resolved", task.name());
+        if self.explain {
+            info!("task '{}' will run: {}", task.name(), reason);
+        }
+
         self.unresolved.remove(&task);
         self.resolved.insert(task.clone());
         self.schedule.push_back(task.clone());
 
         Ok(())
     }
+}
 
-    /// Determines recursively if a task is satisfied. For a task to be satisfied, its dependencies
-    /// must also be satisfied.
-    fn satisfied(&self, task: Rc<Task>) -> Result<bool, Box<Error>> {
-        if !task.satisfied() {
-            return Ok(false);
-        }
 
-        for dependency in task.dependencies() {
-            let dependency = try!(self.graph.get(dependency));
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::error::Error;
+    use std::rc::Rc;
+    use super::*;
+    use task::{NamedTask, SourceFile, Task};
 
-            if !try!(self.satisfied(dependency)) {
-                return Ok(false);
-            }
+    fn task(name: &str, dependencies: &[&str]) -> Rc<Task> {
+        let dependencies: Vec<String> = dependencies.iter().map(|s| s.to_string()).collect();
+        Rc::new(NamedTask::new(name,
+                               None,
+                               dependencies,
+                               None::<fn() -> Result<(), Box<Error>>>))
+    }
+
+    fn graph(tasks: Vec<Rc<Task>>) -> Graph {
+        let mut graph = Graph::new();
+        for task in tasks {
+            graph.insert(task);
         }
+        graph
+    }
+
+    fn names(schedule: VecDeque<Rc<Task>>) -> Vec<String> {
+        schedule.iter().map(|task| task.name().to_string()).collect()
+    }
+
+    #[test]
+    fn solve_puts_dependencies_first() {
+        let graph = graph(vec![task("c", &["a", "b"]), task("b", &["a"]), task("a", &[])]);
+
+        assert_eq!(names(graph.solve(false, false).unwrap()), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn solve_detects_cycles() {
+        let graph = graph(vec![task("a", &["b"]), task("b", &["a"])]);
+        let error = graph.solve(false, false).err().unwrap();
+
+        assert!(error.to_string().starts_with("circular dependency detected"));
+    }
+
+    #[test]
+    fn solve_skips_satisfied_tasks() {
+        let source = env::temp_dir().to_string_lossy().into_owned();
+        let graph = graph(vec![task("build", &[&source]),
+                               Rc::new(SourceFile::new(source.clone()))]);
+
+        assert_eq!(names(graph.solve(true, false).unwrap()), vec!["build"]);
+        assert_eq!(names(graph.solve(false, false).unwrap()), vec![source, "build".to_string()]);
+    }
+
+    #[test]
+    fn missing_dependencies_are_errors() {
+        let graph = graph(vec![task("build", &["missing"])]);
+
+        assert_eq!(graph.solve(false, false).err().unwrap().to_string(),
+                   "task 'missing' not found");
+        assert!(graph.get("nothing").is_err());
+    }
+
+    #[test]
+    fn named_tasks_are_never_satisfied() {
+        let source = env::temp_dir().to_string_lossy().into_owned();
+        let graph = graph(vec![task("build", &[]), Rc::new(SourceFile::new(source.clone()))]);
+
+        assert!(graph.satisfied(&*graph.get(&source).unwrap()).unwrap());
+        assert!(!graph.satisfied(&*graph.get("build").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn exports_edges() {
+        let graph = graph(vec![task("b", &["a", "a"]), task("a", &[])]);
+
+        assert_eq!(graph.dependencies(&*graph.get("b").unwrap()).unwrap(), vec!["a"]);
+        assert!(graph.to_dot().unwrap().contains("    \"b\" -> \"a\";\n"));
 
-        Ok(true)
+        let json = graph.to_json().unwrap();
+        assert_eq!(json["nodes"].len(), 2);
+        assert_eq!(json["edges"][0]["from"], "b");
+        assert_eq!(json["edges"][0]["to"], "a");
     }
 }
@@ -0,0 +1,308 @@
+use libc;
+use std::env;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+
+/// A GNU make compatible jobserver.
+///
+/// A jobserver is a pipe holding one byte, or token, for every job that may run in parallel
+/// besides the first. Before starting another job, a token must be read from the pipe, and it is
+/// written back once the job finishes. Child processes that understand the protocol, such as
+/// `make` and `cargo`, share the same pipe, so the total number of jobs never exceeds the limit.
+///
+/// See <https://www.gnu.org/software/make/manual/html_node/Job-Slots.html>.
+pub struct Jobserver {
+    read: libc::c_int,
+    write: libc::c_int,
+
+    /// Indicates if the file descriptors were opened by us and should be closed when dropped.
+    owned: bool,
+
+    /// Indicates if the jobserver belongs to a parent process.
+    client: bool,
+}
+
+impl Jobserver {
+    /// Creates a new jobserver that allows up to `jobs` jobs to run at once.
+    #[cfg(unix)]
+    pub fn new(jobs: usize) -> Result<Jobserver, Box<Error>> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(format!("failed to create jobserver pipe: {}", io::Error::last_os_error())
+                .into());
+        }
+
+        let jobserver = Jobserver {
+            read: fds[0],
+            write: fds[1],
+            owned: true,
+            client: false,
+        };
+
+        // The first job does not need a token.
+        for _ in 1..jobs {
+            try!(jobserver.release(b'+'));
+        }
+
+        Ok(jobserver)
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_: usize) -> Result<Jobserver, Box<Error>> {
+        Err("jobservers are not supported on this platform".into())
+    }
+
+    /// Connects to the jobserver of a parent `make` process, if there is one.
+    ///
+    /// The jobserver is described by the `MAKEFLAGS` environment variable, either as a pair of
+    /// inherited file descriptors or as the path to a named pipe.
+    #[cfg(unix)]
+    pub fn from_env() -> Option<Jobserver> {
+        use std::os::unix::io::IntoRawFd;
+
+        let flags = match env::var("MAKEFLAGS") {
+            Ok(flags) => flags,
+            Err(_) => return None,
+        };
+
+        let value = match flags.split_whitespace()
+            .filter_map(|flag| {
+                if flag.starts_with("--jobserver-auth=") {
+                    Some(&flag[17..])
+                } else if flag.starts_with("--jobserver-fds=") {
+                    Some(&flag[16..])
+                } else {
+                    None
+                }
+            })
+            .last() {
+            Some(value) => value,
+            None => return None,
+        };
+
+        // Newer versions of make use a named pipe instead of inherited file descriptors.
+        if value.starts_with("fifo:") {
+            return match OpenOptions::new().read(true).write(true).open(&value[5..]) {
+                Ok(file) => {
+                    let fd = file.into_raw_fd();
+                    Some(Jobserver {
+                        read: fd,
+                        write: fd,
+                        owned: true,
+                        client: true,
+                    })
+                }
+                Err(e) => {
+                    warn!("failed to open jobserver '{}': {}", &value[5..], e);
+                    None
+                }
+            };
+        }
+
+        let fds: Vec<libc::c_int> = value.split(',').filter_map(|fd| fd.parse().ok()).collect();
+        if fds.len() != 2 {
+            warn!("invalid jobserver in MAKEFLAGS: '{}'", value);
+            return None;
+        }
+
+        // Make only passes the file descriptors to commands it knows to be recursive; check that
+        // they were actually inherited.
+        for &fd in &fds {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                warn!("jobserver unavailable; was rote run from a recipe without a '+' prefix?");
+                return None;
+            }
+        }
+
+        Some(Jobserver {
+            read: fds[0],
+            write: fds[1],
+            owned: false,
+            client: true,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> Option<Jobserver> {
+        None
+    }
+
+    /// Checks if the jobserver belongs to a parent process rather than being created by us.
+    pub fn is_client(&self) -> bool {
+        self.client
+    }
+
+    /// Exports the jobserver in `MAKEFLAGS` so that child processes share it.
+    pub fn export(&self) {
+        let auth = format!("{},{}", self.read, self.write);
+        env::set_var("MAKEFLAGS",
+                     format!("-j --jobserver-fds={} --jobserver-auth={}", auth, auth));
+    }
+
+    /// Takes a token from the jobserver, blocking until one is available.
+    pub fn acquire(&self) -> io::Result<u8> {
+        let mut token = 0u8;
+
+        loop {
+            let result = unsafe {
+                libc::read(self.read, &mut token as *mut u8 as *mut libc::c_void, 1)
+            };
+
+            if result == 1 {
+                return Ok(token);
+            }
+
+            if result == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "jobserver closed"));
+            }
+
+            let error = io::Error::last_os_error();
+            match error.kind() {
+                io::ErrorKind::Interrupted => {}
+                // The pipe may have been made non-blocking by another process sharing it.
+                io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                _ => return Err(error),
+            }
+        }
+    }
+
+    /// Takes a token from the jobserver, waiting at most `timeout` for one to become available.
+    ///
+    /// Returns `None` if no token became available in time.
+    #[cfg(unix)]
+    pub fn try_acquire(&self, timeout: Duration) -> io::Result<Option<u8>> {
+        let mut poll = libc::pollfd {
+            fd: self.read,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1000000;
+
+        match unsafe { libc::poll(&mut poll, 1, ms as libc::c_int) } {
+            0 => Ok(None),
+            result if result < 0 => {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    Ok(None)
+                } else {
+                    Err(error)
+                }
+            }
+            _ => self.acquire().map(Some),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn try_acquire(&self, _: Duration) -> io::Result<Option<u8>> {
+        self.acquire().map(Some)
+    }
+
+    /// Returns a token to the jobserver.
+    pub fn release(&self, token: u8) -> io::Result<()> {
+        loop {
+            let result = unsafe {
+                libc::write(self.write, &token as *const u8 as *const libc::c_void, 1)
+            };
+
+            if result == 1 {
+                return Ok(());
+            }
+
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
+
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                libc::close(self.read);
+                if self.write != self.read {
+                    libc::close(self.write);
+                }
+            }
+        }
+    }
+}
+
+/// Tokens taken from a jobserver, which are given back when dropped.
+///
+/// Other processes sharing the jobserver can never use a token that is not given back, so keeping
+/// them in a guard makes sure that none are lost when a run ends early.
+pub struct Tokens {
+    jobserver: Arc<Jobserver>,
+    held: Vec<u8>,
+}
+
+impl Tokens {
+    pub fn new(jobserver: Arc<Jobserver>) -> Tokens {
+        Tokens {
+            jobserver: jobserver,
+            held: Vec::new(),
+        }
+    }
+
+    /// Gets the number of tokens held.
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Adds a token acquired from the jobserver.
+    pub fn push(&mut self, token: u8) {
+        self.held.push(token);
+    }
+
+    /// Gives tokens back to the jobserver until no more than `count` are held.
+    pub fn release_to(&mut self, count: usize) {
+        while self.held.len() > count {
+            if let Err(e) = self.jobserver.release(self.held.pop().unwrap()) {
+                warn!("failed to release jobserver token: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Tokens {
+    fn drop(&mut self) {
+        self.release_to(0);
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn tokens_are_given_back_when_dropped() {
+        let jobserver = Arc::new(Jobserver::new(3).unwrap());
+        let wait = Duration::from_millis(10);
+
+        {
+            let mut tokens = Tokens::new(jobserver.clone());
+            tokens.push(jobserver.acquire().unwrap());
+            tokens.push(jobserver.acquire().unwrap());
+            assert_eq!(jobserver.try_acquire(wait).unwrap(), None);
+
+            tokens.release_to(1);
+            assert_eq!(tokens.len(), 1);
+            let token = jobserver.try_acquire(wait).unwrap().unwrap();
+            jobserver.release(token).unwrap();
+        }
+
+        assert!(jobserver.try_acquire(wait).unwrap().is_some());
+        assert!(jobserver.try_acquire(wait).unwrap().is_some());
+        assert_eq!(jobserver.try_acquire(wait).unwrap(), None);
+    }
+}
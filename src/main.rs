@@ -1,7 +1,9 @@
+extern crate crypto;
 extern crate getopts;
 extern crate glob;
 extern crate hyper;
 extern crate json;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate lua;
@@ -11,21 +13,38 @@ extern crate term;
 
 use getopts::Options;
 use runner::Runner;
+use task::{self, Arguments};
 use std::env;
 use std::path;
 use std::process;
+use std::time::Duration;
 
+mod database;
+mod executor;
 mod graph;
+mod jobserver;
 mod logger;
 mod modules;
+mod output;
+mod pattern;
+mod processes;
+mod query;
+mod reaper;
 mod rule;
 mod runner;
 mod runtime;
+mod scheduler;
 mod task;
+mod timer;
+mod trace;
+mod watcher;
 
 
 const ROTE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// File names that are searched for when no Rotefile is given, in order of preference.
+const ROTEFILE_NAMES: &'static [&'static str] = &["Rotefile", "Rotefile.lua"];
+
 
 /// Prints the program usage to the console.
 fn print_usage(options: Options) {
@@ -48,6 +67,42 @@ Rote home page: <https://github.com/sagebind/rote>"
     , options.usage(&short_usage));
 }
 
+/// Gets the file names to look for when searching for a Rotefile.
+///
+/// The list can be changed by setting `ROTEFILE_NAMES` to a comma-separated list of names.
+fn rotefile_names() -> Vec<String> {
+    match env::var("ROTEFILE_NAMES") {
+        Ok(ref value) if !value.trim().is_empty() => {
+            value.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        }
+        _ => ROTEFILE_NAMES.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+/// Finds the Rotefile for a directory by looking in the directory and each of its parents in turn,
+/// the same way that Git finds a repository.
+fn find_rotefile(directory: &path::Path, names: &[String]) -> Option<path::PathBuf> {
+    let mut directory = Some(directory);
+
+    while let Some(current) = directory {
+        for name in names {
+            let path = current.join(name);
+            trace!("looking for '{}'", path.to_string_lossy());
+
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+
+        directory = current.parent();
+    }
+
+    None
+}
+
 /// Parses command-line options and runs retest.
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -55,20 +110,38 @@ fn main() {
     // Parse command-line flags.
     let mut options = Options::new();
     options.optflag("B", "run-all", "Unconditionally run all tasks, including those up-to-date.");
+    options.optflag("", "coroutines", "Run tasks as coroutines in one thread, sharing globals.");
     options.optopt("C", "directory", "Change to DIRECTORY before running tasks.", "DIRECTORY");
+    options.optflag("", "checksum", "Compare file checksums instead of modification times.");
     options.optmulti("D", "var", "Override a variable value.", "NAME=VALUE");
+    options.optflag("", "explain", "Explain why each task has to run.");
     options.optopt("f", "file", "Read FILE as the Rotefile.", "FILE");
+    options.optopt("", "graph", "Print the task graph as FORMAT (dot or json) and exit.", "FORMAT");
     options.optflag("h", "help", "Print this help message and exit.");
     options.optmulti("I", "include-path", "Include PATH in the search path for modules.", "PATH");
     options.optopt("j", "jobs", "The number of jobs to run simultaneously.", "N");
+    options.optflag("", "json", "Print query results as JSON.");
     options.optflag("k", "keep-going", "Keep going if some tasks fail.");
     options.optflag("l", "list", "List all tasks and exit.");
+    options.optopt("", "max-load", "Don't start new tasks if the load average is above LOAD.", "LOAD");
+    options.optopt("o", "output", "How to display task output: passthrough, grouped, or prefixed.", "MODE");
     options.optflag("n", "dry-run", "Simulate running tasks without executing them.");
+    options.optflag("", "no-snapshot", "Evaluate the Rotefile again in every worker thread.");
     options.optflag("q", "quiet", "Supress all non-task output.");
+    options.optflag("", "query", "Answer the query given instead of tasks and exit.");
+    options.optopt("t", "timeout", "Stop tasks that run for longer than SECONDS.", "SECONDS");
+    options.optopt("", "trace", "Write a timeline of the build to FILE in Chrome trace format.", "FILE");
     options.optflagmulti("v", "verbose", "Enable verbose logging.");
     options.optflag("V", "version", "Print the program version and exit.");
+    options.optflag("w", "watch", "Run tasks again whenever the files they depend on change.");
+
+    // Arguments after `--` are passed to the last task given.
+    let (args, extra) = match args.iter().position(|arg| arg == "--") {
+        Some(index) => (&args[1..index], args[index + 1..].to_vec()),
+        None => (&args[1..], Vec::new()),
+    };
 
-    let matches = options.parse(&args[1..]).unwrap_or_else(|err| {
+    let matches = options.parse(args).unwrap_or_else(|err| {
         logger::init(logger::Filter::Error).unwrap();
         error!("{}", err);
         process::exit(2);
@@ -109,14 +182,32 @@ fn main() {
         }
     }
 
-    // Get the full path of the Rotefile to run.
-    let filename = matches.opt_str("file").unwrap_or("Rotefile".to_string());
-    let path = path::Path::new(&filename)
-        .canonicalize()
-        .unwrap_or_else(|_| {
-            error!("the path '{}' is not a file or is not readable", filename);
-            process::exit(1);
-        });
+    // Get the full path of the Rotefile to run. Unless a file is given, search for one in the
+    // current directory and its parents.
+    let path = match matches.opt_str("file") {
+        Some(filename) => {
+            path::Path::new(&filename).canonicalize().unwrap_or_else(|_| {
+                error!("the path '{}' is not a file or is not readable", filename);
+                process::exit(1);
+            })
+        }
+        None => {
+            let names = rotefile_names();
+            let directory = env::current_dir()
+                .and_then(|directory| directory.canonicalize())
+                .unwrap_or_else(|e| {
+                    error!("failed to get the current directory: {}", e);
+                    process::exit(1);
+                });
+
+            find_rotefile(&directory, &names).unwrap_or_else(|| {
+                error!("no {} found in '{}' or any parent directory",
+                       names.join(" or "),
+                       directory.to_string_lossy());
+                process::exit(1);
+            })
+        }
+    };
 
     // Create a new task runner.
     let mut runner = Runner::new(path).unwrap_or_else(|e| {
@@ -164,12 +255,49 @@ fn main() {
         runner.always_run();
     }
 
+    // Toggle checksum mode.
+    if matches.opt_present("checksum") {
+        info!("using checksums to detect changed files");
+        runner.checksums();
+    }
+
     // Toggle keep going.
     if matches.opt_present("keep-going") {
-        info!("errors will be ignored");
+        info!("tasks that do not depend on failed tasks will keep running");
         runner.keep_going();
     }
 
+    // Explain why tasks run.
+    if matches.opt_present("explain") {
+        runner.explain();
+    }
+
+    // Record a timeline of the build.
+    if let Some(path) = matches.opt_str("trace") {
+        runner.trace(path);
+    }
+
+    // Set the output mode.
+    if let Some(mode) = matches.opt_str("output") {
+        match mode.parse() {
+            Ok(mode) => runner.output_mode(mode),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Run tasks as coroutines.
+    if matches.opt_present("coroutines") {
+        runner.coroutines();
+    }
+
+    // Evaluate the Rotefile in every worker thread.
+    if matches.opt_present("no-snapshot") {
+        runner.no_snapshot();
+    }
+
     // Set number of jobs.
     if let Some(jobs) = matches.opt_str("jobs") {
         if let Ok(jobs) = jobs.parse::<usize>() {
@@ -179,6 +307,24 @@ fn main() {
         }
     }
 
+    // Set the default task timeout.
+    if let Some(timeout) = matches.opt_str("timeout") {
+        if let Ok(seconds) = timeout.parse::<f64>() {
+            runner.timeout(Duration::from_millis((seconds.max(0.0) * 1000.0) as u64));
+        } else {
+            warn!("invalid timeout");
+        }
+    }
+
+    // Set the maximum load average.
+    if let Some(load) = matches.opt_str("max-load") {
+        if let Ok(load) = load.parse::<f64>() {
+            runner.max_load(load);
+        } else {
+            warn!("invalid load average");
+        }
+    }
+
     // Load the script.
     if let Err(e) = runner.load() {
         error!("{}", e);
@@ -191,12 +337,74 @@ fn main() {
         return;
     }
 
-    // Get all of the tasks to run.
-    let tasks = matches.free;
+    // Get all of the tasks to run. Arguments of the form `name=value` are passed to the task named
+    // before them, or to the default task if they come first. Anything else is a task, even if it
+    // contains `=`.
+    let query = matches.opt_present("query");
+    let mut tasks: Vec<String> = Vec::new();
+    let mut arguments: Vec<Arguments> = Vec::new();
+    let mut default_arguments = Arguments::default();
+
+    for arg in &matches.free {
+        match arg.find('=') {
+            Some(index) if !query && task::is_argument_name(&arg[..index]) => {
+                let argument = (arg[..index].to_string(), arg[index + 1..].to_string());
+                match arguments.last_mut() {
+                    Some(arguments) => arguments.named.push(argument),
+                    None => default_arguments.named.push(argument),
+                }
+            }
+            _ => {
+                tasks.push(arg.clone());
+                arguments.push(Arguments::default());
+            }
+        }
+    }
+
+    match arguments.last_mut() {
+        Some(arguments) => arguments.positional = extra,
+        None => default_arguments.positional = extra,
+    }
+
+    if !default_arguments.is_empty() && !tasks.is_empty() {
+        error!("task arguments must come after the name of the task they are for");
+        process::exit(2);
+    }
+
+    for (task, arguments) in tasks.iter().zip(arguments) {
+        if !arguments.is_empty() {
+            runner.set_arguments(task.as_str(), arguments);
+        }
+    }
+
+    if !default_arguments.is_empty() {
+        match runner.default_task() {
+            Some(task) => runner.set_arguments(task, default_arguments),
+            None => {
+                error!("no default task defined");
+                process::exit(1);
+            }
+        }
+    }
+
+    let graph_format = match matches.opt_str("graph").map(|format| format.parse()) {
+        Some(Err(e)) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+        Some(Ok(format)) => Some(format),
+        None => None,
+    };
 
     // Run the specified task, or the default if none is specified.
     if let Err(e) = {
-        if tasks.is_empty() {
+        if query {
+            runner.query(&tasks, matches.opt_present("json"))
+        } else if let Some(format) = graph_format {
+            runner.print_graph(&tasks, format)
+        } else if matches.opt_present("watch") {
+            runner.watch(&tasks)
+        } else if tasks.is_empty() {
             runner.run_default()
         } else {
             // Run the specified tasks.
@@ -204,6 +412,8 @@ fn main() {
         }
     } {
         error!("{}", e);
-        process::exit(1);
+
+        // Follow the shell convention for processes terminated by a signal.
+        process::exit(processes::signal().map(|signal| 128 + signal).unwrap_or(1));
     }
 }
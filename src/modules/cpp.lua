@@ -54,7 +54,7 @@ function cpp.binary(options)
         local object_file = file .. ".o"
         table.insert(object_files, object_file)
 
-        rote.create_rule(object_file, function(object_file)
+        rote.create_rule(object_file, {file}, function(object_file)
             local args = {}
             for _, flag in ipairs(compiler_flags) do
                 table.insert(args, flag)
@@ -0,0 +1,196 @@
+-- Produces a string that identifies the behavior of a function. The result
+-- includes the function's bytecode, the values of its upvalues, and the values
+-- of the global variables it refers to, so closures created from the same code
+-- but capturing different values (such as compiler flags) produce different
+-- fingerprints, and so does changing a global variable with -D.
+
+-- Reads the string constants out of a function dumped by string.dump(). Global
+-- variables are looked up by name, so their names are among the constants.
+local function constants(code)
+    local strings = {}
+
+    -- Only the format of Lua 5.3 is understood.
+    if code:sub(1, 5) ~= "\27Lua\83" then
+        return strings
+    end
+
+    local int, size_t, instruction, integer, number = code:byte(13, 17)
+    local pos = 18 + integer + number + 1
+
+    local function read_int()
+        local n
+        n, pos = string.unpack("i" .. int, code, pos)
+        return n
+    end
+
+    local function read_string()
+        local size = code:byte(pos)
+        pos = pos + 1
+        if size == 0xFF then
+            size, pos = string.unpack("I" .. size_t, code, pos)
+        end
+        if size == 0 then
+            return nil
+        end
+
+        local s = code:sub(pos, pos + size - 2)
+        pos = pos + size - 1
+        return s
+    end
+
+    local function read_function()
+        read_string()
+        pos = pos + 2 * int + 3
+        pos = pos + read_int() * instruction
+
+        for _ = 1, read_int() do
+            local t = code:byte(pos)
+            pos = pos + 1
+
+            if t == 1 then
+                pos = pos + 1
+            elseif t == 3 then
+                pos = pos + number
+            elseif t == 19 then
+                pos = pos + integer
+            elseif t == 4 or t == 20 then
+                table.insert(strings, read_string())
+            end
+        end
+
+        pos = pos + read_int() * 2
+        for _ = 1, read_int() do
+            read_function()
+        end
+
+        -- Debug information, which is empty when stripped.
+        pos = pos + read_int() * int
+        for _ = 1, read_int() do
+            read_string()
+            pos = pos + 2 * int
+        end
+        for _ = 1, read_int() do
+            read_string()
+        end
+    end
+
+    read_function()
+    return strings
+end
+
+-- Gets the sorted names of the global variables a function may refer to,
+-- including variables expanded in command arguments, such as "$CFLAGS".
+local function global_names(code)
+    local names = {}
+    for _, constant in ipairs(constants(code)) do
+        if constant:match("^[%a_][%w_]*$") then
+            names[constant] = true
+        end
+        for name in constant:gmatch("%$([%w_]+)") do
+            names[name] = true
+        end
+    end
+
+    local sorted = {}
+    for name in pairs(names) do
+        table.insert(sorted, name)
+    end
+    table.sort(sorted)
+
+    return sorted
+end
+
+local function serialize(value, seen, modules, out)
+    local t = type(value)
+
+    if t == "function" or t == "table" then
+        if seen[value] then
+            table.insert(out, "<seen>")
+            return
+        end
+        seen[value] = true
+
+        -- Modules are identified by name; their contents are code, not settings.
+        if modules[value] then
+            table.insert(out, "module:" .. modules[value])
+            return
+        end
+    end
+
+    if t == "function" then
+        -- Native functions cannot be dumped, but their upvalues are still useful.
+        local ok, code = pcall(string.dump, value, true)
+        table.insert(out, ok and code or "<native>")
+
+        local env
+        local i = 1
+        while true do
+            local name, upvalue = debug.getupvalue(value, i)
+            if not name then
+                break
+            end
+
+            -- The global environment is far too large to fingerprint, so only
+            -- the globals the function refers to are included below.
+            if name == "_ENV" then
+                env = upvalue
+            else
+                table.insert(out, name)
+                serialize(upvalue, seen, modules, out)
+            end
+
+            i = i + 1
+        end
+
+        if ok and type(env) == "table" then
+            local parsed, names = pcall(global_names, code)
+            for _, name in ipairs(parsed and names or {}) do
+                -- Unset globals fall back to environment variables.
+                local global = env[name]
+                if global ~= nil then
+                    table.insert(out, "global:" .. name)
+                    serialize(global, seen, modules, out)
+                end
+            end
+        end
+    elseif t == "table" then
+        local keys = {}
+        for key in pairs(value) do
+            table.insert(keys, key)
+        end
+        table.sort(keys, function(a, b)
+            return tostring(a) < tostring(b)
+        end)
+
+        table.insert(out, "{")
+        for _, key in ipairs(keys) do
+            serialize(key, seen, modules, out)
+            serialize(value[key], seen, modules, out)
+        end
+        table.insert(out, "}")
+    elseif t == "string" or t == "number" or t == "boolean" or t == "nil" then
+        table.insert(out, t .. ":" .. tostring(value))
+    else
+        table.insert(out, t)
+    end
+end
+
+return function(f)
+    -- A module loaded under more than one name always gets the same one.
+    local modules = {}
+    for name, module in pairs(package.loaded) do
+        if type(module) == "table" or type(module) == "function" then
+            if not modules[module] or name < modules[module] then
+                modules[module] = name
+            end
+        end
+    end
+
+    local out = {}
+    serialize(f, {}, modules, out)
+
+    -- Encode as hex so that the result is safe to pass around as text.
+    return (table.concat(out, "\0"):gsub(".", function(c)
+        return ("%02x"):format(c:byte())
+    end))
+end
@@ -4,12 +4,18 @@ use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 
 
+/// Gets a path argument, resolved relative to the directory of the Rotefile that is running.
+fn check_path(runtime: &Runtime, index: i32) -> String {
+    let path = runtime.state().check_string(index).to_string();
+    runtime.environment().resolve(&path)
+}
+
 /// Checks if a file exists and is readable.
 ///
 /// # Lua arguments
 /// * `path: string`            - Path to the file to check.
 fn exists(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     runtime.state().push_bool(fs::metadata(path).is_ok());
 
@@ -21,7 +27,7 @@ fn exists(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `path: string`            - Path to check.
 fn is_dir(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     let metadata = fs::metadata(path);
     runtime.state().push_bool(metadata.is_ok() && metadata.unwrap().file_type().is_dir());
@@ -34,7 +40,7 @@ fn is_dir(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `path: string`            - Path to check.
 fn is_file(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     let metadata = fs::metadata(path);
     runtime.state().push_bool(metadata.is_ok() && metadata.unwrap().file_type().is_file());
@@ -47,7 +53,7 @@ fn is_file(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `path: string`            - Path to check.
 fn is_symlink(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     let metadata = fs::metadata(path);
     runtime.state().push_bool(metadata.is_ok() && metadata.unwrap().file_type().is_symlink());
@@ -61,7 +67,7 @@ fn is_symlink(runtime: Runtime) -> ScriptResult {
 /// * `path: string`            - Path to create the directory.
 fn mkdir(runtime: Runtime) -> ScriptResult {
     // Get the path as the first argument.
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     if fs::create_dir(&path).is_err() {
         return Err(format!("failed to create directory \"{}\"", path).into());
@@ -76,8 +82,8 @@ fn mkdir(runtime: Runtime) -> ScriptResult {
 /// * `source: string`          - Path of the file to copy.
 /// * `dest: string`            - Path to copy the file to.
 fn copy(runtime: Runtime) -> ScriptResult {
-    let source = runtime.state().check_string(1).to_string();
-    let dest = runtime.state().check_string(2).to_string();
+    let source = check_path(&runtime, 1);
+    let dest = check_path(&runtime, 2);
 
     if fs::copy(&source, dest).is_err() {
         return Err(format!("failed to copy \"{}\"", source).into());
@@ -92,8 +98,8 @@ fn copy(runtime: Runtime) -> ScriptResult {
 /// * `source: string`          - Path of the file to move.
 /// * `dest: string`            - Path to move the file to.
 fn rename(runtime: Runtime) -> ScriptResult {
-    let source = runtime.state().check_string(1).to_string();
-    let destination = runtime.state().check_string(2).to_string();
+    let source = check_path(&runtime, 1);
+    let destination = check_path(&runtime, 2);
 
     if fs::rename(source, destination).is_err() {
         return Err("no such file or directory".into());
@@ -107,7 +113,7 @@ fn rename(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `path: string`            - Path of the file or directory to remove.
 fn remove(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     if let Ok(metadata) = fs::metadata(&path) {
         if metadata.file_type().is_dir() {
@@ -129,7 +135,7 @@ fn remove(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `path: string`            - Path of the file to read from.
 fn get(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
 
     let file = File::open(path);
 
@@ -155,7 +161,7 @@ fn get(runtime: Runtime) -> ScriptResult {
 /// * `path: string`            - Path to the file to write to.
 /// * `contents: string`        - The contents to write.
 fn put(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
     let contents = String::from(runtime.state().check_string(2));
 
     let file = OpenOptions::new()
@@ -182,7 +188,7 @@ fn put(runtime: Runtime) -> ScriptResult {
 /// * `path: string`            - Path to the file to append to.
 /// * `contents: string`        - The contents to append.
 fn append(runtime: Runtime) -> ScriptResult {
-    let path = runtime.state().check_string(1).to_string();
+    let path = check_path(&runtime, 1);
     let contents = String::from(runtime.state().check_string(2));
 
     let file = OpenOptions::new()
@@ -213,7 +219,7 @@ fn combine(runtime: Runtime) -> ScriptResult {
     }
 
     // Open the output file for writing.
-    let dest = runtime.state().check_string(2).to_string();
+    let dest = check_path(&runtime, 2);
     let out_file = OpenOptions::new()
                        .write(true)
                        .truncate(true)
@@ -228,7 +234,8 @@ fn combine(runtime: Runtime) -> ScriptResult {
 
     // Walk through each path in the sources table and write their contents.
     for (_, value) in runtime.iter(1) {
-        let source = runtime.state().to_str_in_place(value).unwrap().to_string();
+        let source = runtime.environment()
+            .resolve(runtime.state().to_str_in_place(value).unwrap());
 
         let in_file = File::open(&source);
         if in_file.is_err() {
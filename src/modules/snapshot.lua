@@ -0,0 +1,348 @@
+-- Takes a snapshot of the tasks and rules defined by a Rotefile, so that worker
+-- threads can define the same tasks without evaluating the Rotefile again.
+--
+-- Every call that defines a task or rule is recorded along with its arguments.
+-- The recorded calls and the globals set by the Rotefile are then written out
+-- as a Lua chunk that recreates them. Functions are copied as bytecode along
+-- with the values of their upvalues, and values shared between functions stay
+-- shared.
+--
+-- Values that worker threads already have, such as `exec`, the `string` table,
+-- or Rote modules like `require "fs"`, are referred to by name instead of
+-- copied. Changes the Rotefile made to those tables are copied as well. Modules
+-- loaded from files by the Rotefile are copied like any other value, so that
+-- workers do not run them again.
+
+local snapshot = {}
+
+-- Values of all globals before the Rotefile was run, or nil if not recording.
+local initial = nil
+
+-- Tables that existed before the Rotefile was run, mapped to an expression
+-- that gets the table in a worker.
+local tables = nil
+
+-- The contents of each of those tables before the Rotefile was run.
+local contents = nil
+
+-- Rote modules first loaded by the Rotefile, which workers load for themselves.
+local loaded = nil
+
+-- Recorded definitions, in the order they were made.
+local definitions = {}
+
+-- Quotes a string as a Lua string literal that is always valid UTF-8.
+local function quote(s)
+    return '"' .. s:gsub('[%c"\\\128-\255]', function(c)
+        return ("\\%03d"):format(c:byte())
+    end) .. '"'
+end
+
+-- Writes a number as a Lua expression that produces exactly the same number.
+local function number(n)
+    if math.type(n) == "integer" then
+        return n == math.mininteger and "math.mininteger" or ("%d"):format(n)
+    elseif n ~= n then
+        return "(0/0)"
+    elseif n == math.huge then
+        return "(1/0)"
+    elseif n == -math.huge then
+        return "(-1/0)"
+    end
+
+    -- Keep floats that happen to be whole numbers from turning into integers.
+    local s = ("%.17g"):format(n)
+    if not s:find("[.e]") then
+        s = s .. ".0"
+    end
+
+    return s
+end
+
+local function hex(s)
+    return (s:gsub(".", function(c)
+        return ("%02x"):format(c:byte())
+    end))
+end
+
+-- Makes a shallow copy of a table.
+local function copy(t)
+    local result = {}
+    for key, value in pairs(t) do
+        result[key] = value
+    end
+    return result
+end
+
+-- Checks if a function is implemented natively, and so cannot be copied.
+local function is_native(f)
+    return type(f) == "function" and debug.getinfo(f, "S").what == "C"
+end
+
+-- Finds the values that worker threads already have, mapped to an expression
+-- that gets the value in a worker.
+local function known_values()
+    local known = {[_G] = "G"}
+
+    local function add(value, expression)
+        local t = type(value)
+        if (t == "table" or t == "function") and known[value] == nil then
+            known[value] = expression
+        end
+    end
+
+    -- Only what the tables held before the Rotefile was run is already known;
+    -- anything the Rotefile added to them has to be copied.
+    for t, expression in pairs(tables) do
+        add(t, expression)
+    end
+
+    for key, value in pairs(initial) do
+        if type(key) == "string" then
+            add(value, ("G[%s]"):format(quote(key)))
+        end
+    end
+
+    for t, expression in pairs(tables) do
+        for key, value in pairs(contents[t]) do
+            if type(key) == "string" then
+                add(value, ("%s[%s]"):format(expression, quote(key)))
+            end
+        end
+    end
+
+    -- The native functions in Rote modules loaded by the Rotefile are loaded by
+    -- workers just the same.
+    for module in pairs(loaded) do
+        for key, value in pairs(module) do
+            if type(key) == "string" and is_native(value) then
+                add(value, ("%s[%s]"):format(tables[module], quote(key)))
+            end
+        end
+    end
+
+    return known
+end
+
+-- Adds the Rote modules that the Rotefile loaded to the tables that workers
+-- already have. Their contents are compared with a newly loaded copy, since
+-- workers load the module for themselves.
+local function add_loaded_modules()
+    for name, module in pairs(package.loaded) do
+        if package.preload[name] and type(module) == "table" and not tables[module] then
+            local fresh = package.preload[name](name)
+
+            if type(fresh) == "table" then
+                tables[module] = ("require(%s)"):format(quote(name))
+                contents[module] = fresh
+                loaded[module] = true
+            end
+        end
+    end
+end
+
+-- Starts recording definitions, and remembers the current globals, modules and
+-- their contents so that only the changes made by the Rotefile are included in
+-- the snapshot.
+function snapshot.start()
+    initial = copy(_G)
+    tables = {}
+    contents = {}
+    loaded = {}
+
+    local function add(value, expression)
+        if type(value) == "table" and value ~= _G and tables[value] == nil then
+            tables[value] = expression
+        end
+    end
+
+    for name, module in pairs(package.loaded) do
+        add(module, ("require(%s)"):format(quote(name)))
+    end
+
+    for key, value in pairs(initial) do
+        if type(key) == "string" then
+            add(value, ("G[%s]"):format(quote(key)))
+        end
+    end
+
+    -- Tables inside modules and global tables, such as `package.loaded`.
+    for t, expression in pairs(copy(tables)) do
+        for key, value in pairs(t) do
+            if type(key) == "string" then
+                add(value, ("%s[%s]"):format(expression, quote(key)))
+            end
+        end
+    end
+
+    for t in pairs(tables) do
+        contents[t] = copy(t)
+    end
+end
+
+-- Records a call defining a task or rule.
+function snapshot.record(kind, namespace, directory, ...)
+    if initial then
+        table.insert(definitions, {kind, namespace, directory, table.pack(...)})
+    end
+end
+
+-- Writes the recorded definitions and globals as a Lua chunk. Raises an error
+-- if any of the values involved cannot be copied.
+function snapshot.save()
+    if not initial then
+        error("no definitions were recorded", 0)
+    end
+
+    add_loaded_modules()
+    local known = known_values()
+    local lines = {
+        "local G, v, load = _G, {}, load",
+        "local setupvalue, upvaluejoin = debug.setupvalue, debug.upvaluejoin",
+        "local function unhex(s)",
+        "    return (s:gsub('..', function(h) return string.char(tonumber(h, 16)) end))",
+        "end",
+    }
+    local ids = {}
+    local count = 0
+    local upvalues = {}
+
+    local function emit(line)
+        lines[#lines + 1] = line
+    end
+
+    local function new_ref(value)
+        count = count + 1
+        ids[value] = count
+        return "v[" .. count .. "]"
+    end
+
+    local function serialize(value, path)
+        local t = type(value)
+
+        if t == "nil" or t == "boolean" then
+            return tostring(value)
+        elseif t == "number" then
+            return number(value)
+        elseif t == "string" then
+            return quote(value)
+        elseif t ~= "table" and t ~= "function" then
+            error(("cannot copy %s to worker threads: it is a %s"):format(path, t), 0)
+        elseif ids[value] then
+            return "v[" .. ids[value] .. "]"
+        elseif known[value] then
+            return known[value]
+        end
+
+        if t == "table" then
+            local ref = new_ref(value)
+            emit(ref .. " = {}")
+
+            for key, item in pairs(value) do
+                local item_path = ("%s[%s]"):format(path, tostring(key))
+                emit(("%s[%s] = %s"):format(ref,
+                                            serialize(key, item_path),
+                                            serialize(item, item_path)))
+            end
+
+            local metatable = getmetatable(value)
+            if metatable ~= nil then
+                local metatable_path = path .. " metatable"
+                emit(("setmetatable(%s, %s)"):format(ref, serialize(metatable, metatable_path)))
+            end
+
+            return ref
+        end
+
+        local ok, code = pcall(string.dump, value)
+        if not ok then
+            error(("cannot copy %s to worker threads: it is a native function"):format(path), 0)
+        end
+
+        local ref = new_ref(value)
+        emit(("%s = load(unhex(%q), nil, 'b')"):format(ref, hex(code)))
+
+        local i = 1
+        while true do
+            local name, upvalue = debug.getupvalue(value, i)
+            if not name then
+                break
+            end
+
+            -- Closures sharing a local variable keep sharing it.
+            local id = debug.upvalueid(value, i)
+            if upvalues[id] then
+                emit(("upvaluejoin(%s, %d, %s)"):format(ref, i, upvalues[id]))
+            else
+                upvalues[id] = ("%s, %d"):format(ref, i)
+                local upvalue_path = ("%s (upvalue '%s')"):format(path, name)
+                emit(("setupvalue(%s, %d, %s)"):format(ref, i, serialize(upvalue, upvalue_path)))
+            end
+
+            i = i + 1
+        end
+
+        return ref
+    end
+
+    -- Changes to tables that workers already have, such as a function added to
+    -- `string`, or a module loaded into `package.loaded`.
+    for t, expression in pairs(tables) do
+        local before = contents[t]
+
+        for key, value in pairs(t) do
+            -- A native function in a newly loaded module never equals the one
+            -- the Rotefile has, but the worker has the same function.
+            local native = loaded[t] and is_native(before[key]) and is_native(value)
+
+            if not rawequal(before[key], value) and not native then
+                local path = ("%s[%s]"):format(expression, tostring(key))
+                emit(("%s[%s] = %s"):format(expression,
+                                            serialize(key, path),
+                                            serialize(value, path)))
+            end
+        end
+
+        for key in pairs(before) do
+            if rawget(t, key) == nil then
+                local path = ("%s[%s]"):format(expression, tostring(key))
+                emit(("%s[%s] = nil"):format(expression, serialize(key, path)))
+            end
+        end
+    end
+
+    emit("local globals = {}")
+    for key, value in pairs(_G) do
+        if type(key) == "string" and not rawequal(initial[key], value) then
+            local path = ("global '%s'"):format(key)
+            emit(("globals[%s] = %s"):format(quote(key), serialize(value, path)))
+        end
+    end
+
+    emit("local definitions = {}")
+    for i, definition in ipairs(definitions) do
+        local path = ("%s '%s'"):format(definition[1], tostring(definition[4][1]))
+        emit(("definitions[%d] = %s"):format(i, serialize(definition, path)))
+    end
+
+    emit("return globals, definitions")
+    return table.concat(lines, "\n")
+end
+
+-- Recreates the globals and definitions in a snapshot, calling `define` with
+-- the arguments of each recorded call.
+function snapshot.restore(source, define)
+    local chunk = assert(load(source, "=snapshot", "t"))
+    local globals, definitions = chunk()
+
+    for key, value in pairs(globals) do
+        rawset(_G, key, value)
+    end
+
+    for _, definition in ipairs(definitions) do
+        local args = definition[4]
+        define(definition[1], definition[2], definition[3], table.unpack(args, 1, args.n))
+    end
+end
+
+return snapshot
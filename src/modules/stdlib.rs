@@ -1,14 +1,29 @@
+use database::{self, CommandLine};
 use glob;
+use json::JsonValue;
 use lua;
+use pattern::{self, Pattern};
 use regex::{Captures, Regex};
 use rule::Rule;
-use runtime::{Runtime, ScriptResult};
+use reaper::Process;
+use runtime::{self, Reply, Runtime, Scope, ScriptResult};
 use std::env;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::error::Error;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::str;
-use task::NamedTask;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use task::{self, NamedTask, Param, PreparedCall, Value};
+
+
+/// Source of the function used to fingerprint rule actions.
+const FINGERPRINT_SOURCE: &'static str = include_str!("fingerprint.lua");
+
+/// Source of the module used to copy task and rule definitions to worker threads.
+const SNAPSHOT_SOURCE: &'static str = include_str!("snapshot.lua");
 
 
 /// Expands global and environment variables inside a given string.
@@ -28,6 +43,207 @@ pub fn expand_string(input: &str, runtime: Runtime) -> String {
     })
 }
 
+/// Reads a list of names, such as dependencies, from a task or rule definition table.
+///
+/// Only the sequential part of the table is read; named fields are options.
+fn get_list(runtime: &Runtime, index: i32) -> Vec<String> {
+    runtime.iter(index)
+        .filter(|&(key, _)| runtime.state().type_of(key) == Some(lua::Type::Number))
+        .map(|(_, value)| runtime.state().to_str_in_place(value).unwrap().to_string())
+        .collect()
+}
+
+/// Reads a boolean option from a task or rule definition table.
+fn get_bool_option(runtime: &Runtime, index: i32, name: &str) -> bool {
+    runtime.state().get_field(index, name);
+    let value = runtime.state().to_bool(-1);
+    runtime.state().pop(1);
+
+    value
+}
+
+/// Reads a numeric option from a task or rule definition table.
+fn get_number_option(runtime: &Runtime, index: i32, name: &str) -> Option<f64> {
+    runtime.state().get_field(index, name);
+    let value = if runtime.state().is_number(-1) {
+        Some(runtime.state().to_number(-1))
+    } else {
+        None
+    };
+    runtime.state().pop(1);
+
+    value
+}
+
+/// Reads a string option from a task or rule definition table.
+fn get_string_option(runtime: &Runtime, index: i32, name: &str) -> Option<String> {
+    runtime.state().get_field(index, name);
+    let value = if runtime.state().is_string(-1) {
+        runtime.state().to_str(-1).map(|s| s.to_string())
+    } else {
+        None
+    };
+    runtime.state().pop(1);
+
+    value
+}
+
+/// Reads an option that can be either a single string or a list of strings from a task or rule
+/// definition table.
+fn get_list_option(runtime: &Runtime, index: i32, name: &str) -> Vec<String> {
+    runtime.state().get_field(index, name);
+    let top = runtime.state().get_top();
+
+    let value = match runtime.state().type_of(top) {
+        Some(lua::Type::Table) => get_list(runtime, top),
+        Some(lua::Type::String) => vec![runtime.state().to_str(top).unwrap().to_string()],
+        _ => Vec::new(),
+    };
+    runtime.state().pop(1);
+
+    value
+}
+
+/// Reads the parameter declarations of a task definition table.
+///
+/// Parameters are declared as a list of tables, each with the parameter name as its first item,
+/// and optional `type`, `default`, `description`, and `required` fields.
+fn get_params_option(runtime: &Runtime, index: i32) -> Result<Vec<Param>, Box<Error>> {
+    runtime.state().get_field(index, "params");
+    let top = runtime.state().get_top();
+    let mut params = Vec::new();
+
+    if runtime.state().type_of(top) == Some(lua::Type::Table) {
+        for (key, value) in runtime.iter(top) {
+            if runtime.state().type_of(key) != Some(lua::Type::Number) {
+                continue;
+            }
+
+            if runtime.state().type_of(value) != Some(lua::Type::Table) {
+                return Err("parameters must be declared as tables".into());
+            }
+
+            let position = runtime.state().to_number(key);
+            let param = try!(get_param(runtime, value));
+            params.push((position, param));
+        }
+    }
+    runtime.state().pop(1);
+
+    // Keep the parameters in the order they were declared.
+    params.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    Ok(params.into_iter().map(|(_, param)| param).collect())
+}
+
+/// Reads a single parameter declaration.
+fn get_param(runtime: &Runtime, index: i32) -> Result<Param, Box<Error>> {
+    runtime.state().raw_geti(index, 1);
+    let name = if runtime.state().is_string(-1) {
+        runtime.state().to_str(-1).map(|s| s.to_string())
+    } else {
+        None
+    };
+    runtime.state().pop(1);
+
+    let name = match name {
+        Some(name) => name,
+        None => return Err("parameter declarations must start with the parameter name".into()),
+    };
+
+    if !task::is_argument_name(&name) {
+        return Err(format!("invalid parameter name '{}'", name).into());
+    }
+
+    let kind = match get_string_option(runtime, index, "type").map(|kind| kind.parse()) {
+        Some(Ok(kind)) => kind,
+        Some(Err(e)) => return Err(format!("parameter '{}': {}", name, e).into()),
+        None => task::ParamType::String,
+    };
+
+    // Defaults can be given as any scalar value.
+    runtime.state().get_field(index, "default");
+    let default = match runtime.state().type_of(-1) {
+        Some(lua::Type::Boolean) => Some(runtime.state().to_bool(-1).to_string()),
+        Some(lua::Type::Nil) | None => None,
+        _ => runtime.state().to_str_in_place(-1).map(|s| s.to_string()),
+    };
+    runtime.state().pop(1);
+
+    // Catch bad defaults when the task is defined, rather than when it runs.
+    if let Some(ref default) = default {
+        if let Err(e) = kind.parse(default) {
+            return Err(format!("parameter '{}' has an invalid default: {}", name, e).into());
+        }
+    }
+
+    // Parameters without a default are required unless stated otherwise.
+    runtime.state().get_field(index, "required");
+    let required = if runtime.state().is_nil(-1) {
+        default.is_none()
+    } else {
+        runtime.state().to_bool(-1)
+    };
+    runtime.state().pop(1);
+
+    Ok(Param {
+        name: name,
+        description: get_string_option(runtime, index, "description"),
+        kind: kind,
+        default: default,
+        required: required,
+    })
+}
+
+/// Reads an option given in seconds from a task or rule definition table.
+fn get_duration_option(runtime: &Runtime, index: i32, name: &str) -> Option<Duration> {
+    get_number_option(runtime, index, name)
+        .map(|seconds| Duration::from_millis((seconds.max(0.0) * 1000.0) as u64))
+}
+
+/// Computes a fingerprint for the function at the given stack index.
+///
+/// The fingerprint covers the function's bytecode, the values it captures and the values of the
+/// global variables it refers to, so it changes whenever the commands the function would run are
+/// changed, including by setting a variable with `-D`.
+fn get_fingerprint(runtime: &Runtime, index: i32) -> Option<String> {
+    runtime.reg_get("rote.fingerprint");
+    runtime.state().push_value(index);
+
+    let result = match runtime.call(1, 1, 0) {
+        Ok(_) => runtime.state().to_str(-1).map(database::digest_str),
+        Err(e) => {
+            warn!("failed to fingerprint function: {}", e);
+            None
+        }
+    };
+
+    runtime.state().pop(1);
+    result
+}
+
+/// Records the arguments that a task or rule is being defined with, so that the definition can be
+/// replayed in worker threads without evaluating the Rotefile again.
+fn record_definition(runtime: &Runtime, kind: &str) {
+    let args = runtime.state().get_top();
+    let scope = runtime.environment().scope();
+
+    runtime.reg_get("rote.snapshot");
+    runtime.state().get_field(-1, "record");
+    runtime.state().push_string(kind);
+    runtime.state().push_string(&scope.namespace);
+    runtime.state().push_string(&scope.directory);
+    for i in 1..args + 1 {
+        runtime.state().push_value(i);
+    }
+
+    if let Err(e) = runtime.call(args + 3, 0, 0) {
+        warn!("failed to record definition: {}", e);
+        runtime.state().pop(1);
+    }
+
+    runtime.state().pop(1);
+}
+
 fn get_next_description(runtime: Runtime) -> Option<String> {
     runtime.reg_get("rote.nextDescription");
 
@@ -59,27 +275,92 @@ fn change_dir(runtime: Runtime) -> ScriptResult {
 /// Defines a new rule.
 ///
 /// # Lua arguments
-/// * `pattern: string`      - The name of the task.
+/// * `pattern: string`      - The output file pattern, or a table of patterns if the rule produces
+///                            more than one output.
 /// * `description: string`  - A description of the task. (Optional)
-/// * `dependencies: table`  - A list of task names that the rule depends on. (Optional)
+/// * `dependencies: table`  - A list of task names that the rule depends on, along with any named
+///                            options. (Optional)
 /// * `func: function`       - A function that should be called when the rule is run. (Optional)
+///
+/// # Options
+/// * `checksum: boolean`    - Compare file contents instead of modification times.
+/// * `regex: boolean`       - Treat the output patterns as regular expressions.
+/// * `priority: number`     - Priority over other rules that match a file equally well.
+/// * `pool: string`         - The pool that tasks created by the rule run in.
+/// * `timeout: number`      - Number of seconds the rule action may run before it is stopped.
+/// * `retries: number`      - Number of times to run the rule action again if it fails.
+/// * `retry_delay: number`  - Number of seconds to wait before running the action again.
 fn create_rule(runtime: Runtime) -> ScriptResult {
-    let pattern = runtime.state().check_string(1).to_string();
+    record_definition(&runtime, "rule");
+
+    let sources = if runtime.state().is_table(1) {
+        get_list(&runtime, 1)
+    } else {
+        vec![runtime.state().check_string(1).to_string()]
+    };
+
+    if sources.is_empty() {
+        return Err("rule must have at least one output".into());
+    }
+
     let mut func_index = 3;
+    let mut checksum = runtime.environment().checksums();
+    let mut regex = false;
+    let mut priority = None;
+    let mut pool = None;
+    let mut timeout = None;
+    let mut retries = None;
+    let mut retry_delay = None;
+    let mut fingerprint = None;
 
     // Get the list of dependencies if given.
     let deps = if runtime.state().type_of(2) == Some(lua::Type::Table) {
-        runtime.iter(2)
-            .map(|(_, value)| runtime.state().to_str_in_place(value).unwrap().to_string())
-            .collect()
+        checksum = checksum || get_bool_option(&runtime, 2, "checksum");
+        regex = get_bool_option(&runtime, 2, "regex");
+        priority = get_number_option(&runtime, 2, "priority");
+        pool = get_string_option(&runtime, 2, "pool");
+        timeout = get_duration_option(&runtime, 2, "timeout");
+        retries = get_number_option(&runtime, 2, "retries");
+        retry_delay = get_duration_option(&runtime, 2, "retry_delay");
+        get_list(&runtime, 2)
     } else {
         func_index -= 1;
         Vec::new()
     };
 
+    // Parse the output patterns. Other outputs cannot be derived from a regular expression, so
+    // regular expression rules are limited to a single output.
+    if regex && sources.len() > 1 {
+        return Err("regex rules can only have one output".into());
+    }
+
+    // Outputs are relative to the directory of the Rotefile defining the rule.
+    let scope = runtime.environment().scope();
+
+    let mut patterns = Vec::new();
+    for source in sources {
+        patterns.push(try!(if !regex {
+            Pattern::new(scope.file_path(&source))
+        } else if scope.directory.is_empty() {
+            Pattern::regex(source)
+        } else {
+            Pattern::regex_in(&scope.directory, source)
+        }));
+    }
+
+    // Any of the outputs may be the one matched, so every stem that the dependencies refer to
+    // must be captured by every output pattern.
+    for pattern in &patterns {
+        for dep in &deps {
+            try!(pattern.check_template(dep));
+        }
+    }
+
     // Get the task function if given.
     runtime.state().push_value(func_index);
     let func = if runtime.state().type_of(-1) == Some(lua::Type::Function) {
+        fingerprint = get_fingerprint(&runtime, func_index);
+
         // Get a portable reference to the task function.
         Some(runtime.state().reference(lua::REGISTRYINDEX))
     } else {
@@ -87,25 +368,105 @@ fn create_rule(runtime: Runtime) -> ScriptResult {
         None
     };
 
+    // Pushes the rule function and its arguments onto the stack, and sets up the environment for
+    // it to run. Returns the number of arguments, and the scope to restore afterwards.
     let closure_env = runtime.clone();
-    let callback = func.map(|func| {
-        move |name: &str| {
+    let prepare = func.map(|func| {
+        Rc::new(move |outputs: &[String], captures: &pattern::Captures| -> (i32, Scope) {
             // Get the function reference onto the Lua stack.
             closure_env.state().raw_geti(lua::REGISTRYINDEX, func.value() as i64);
 
-            // Push the synthesized name onto the stack.
-            closure_env.state().push(name);
+            // Push the synthesized name onto the stack, followed by a table of all outputs. Both
+            // are relative to the directory of the Rotefile defining the rule.
+            let relative: Vec<String> = outputs.iter()
+                .map(|output| scope.relative_path(output))
+                .collect();
+            closure_env.state().push(relative[0].as_str());
+            closure_env.state().create_table(relative.len() as i32, 0);
+            for (i, output) in relative.iter().enumerate() {
+                closure_env.state().push(output.as_str());
+                closure_env.state().raw_seti(-2, i as i64 + 1);
+            }
 
+            // Push a table of the captured stems, both by position and by name.
+            closure_env.state().create_table(captures.positional.len() as i32,
+                                             captures.named.len() as i32);
+            for (i, stem) in captures.positional.iter().enumerate() {
+                closure_env.state().push(stem.as_str());
+                closure_env.state().raw_seti(-2, i as i64 + 1);
+            }
+            for (name, stem) in &captures.named {
+                closure_env.state().push(stem.as_str());
+                closure_env.state().set_field(-2, name);
+            }
+
+            let outer = closure_env.environment().set_scope(scope.clone());
+            closure_env.environment().set_current_task(outputs[0].as_str());
+
+            (3, outer)
+        })
+    });
+
+    let closure_env = runtime.clone();
+    let callback = prepare.clone().map(|prepare| {
+        move |outputs: &[String], captures: &pattern::Captures| {
             // Invoke the task function.
-            closure_env.environment().set_current_task(name);
-            let result = closure_env.call(1, 0, 0).map(|_| ()).map_err(|e| e.into());
+            let (nargs, outer) = prepare(outputs, captures);
+            let result = closure_env.call(nargs, 0, 0)
+                .map(|_| ())
+                .map_err(|e| e.into());
             closure_env.environment().clear_current_task();
+            closure_env.environment().set_scope(outer);
 
             result
         }
     });
 
-    runtime.environment().create_rule(Rule::new(pattern, deps, callback));
+    let mut rule = Rule::new(patterns, deps, callback);
+
+    // When run as a coroutine, the executor calls the function instead, and restores the
+    // environment once it returns.
+    if let Some(prepare) = prepare {
+        rule.set_start(move |outputs, captures| {
+            Ok(PreparedCall {
+                nargs: prepare(outputs, captures).0,
+            })
+        });
+    }
+    rule.set_database(runtime.environment().database());
+
+    // Outputs are rebuilt when the commands that built them would now run differently.
+    let commands_env = runtime.clone();
+    let expand_env = runtime.clone();
+    rule.track_commands(move || commands_env.environment().take_commands(),
+                        move |arg| expand_string(arg, expand_env.clone()));
+
+    if let Some(fingerprint) = fingerprint {
+        rule.set_fingerprint(fingerprint);
+    }
+
+    if checksum {
+        rule.use_checksums();
+    }
+
+    if let Some(priority) = priority {
+        rule.set_priority(priority as i64);
+    }
+
+    if let Some(pool) = pool {
+        rule.set_pool(pool);
+    }
+
+    if let Some(timeout) = timeout {
+        rule.set_timeout(timeout);
+    }
+
+    if let Some(retries) = retries {
+        rule.set_retries(retries.max(0.0) as u32,
+                         retry_delay.unwrap_or(Duration::from_secs(0)));
+    }
+
+    runtime.environment().create_rule(rule);
     Ok(0)
 }
 
@@ -114,18 +475,42 @@ fn create_rule(runtime: Runtime) -> ScriptResult {
 /// # Lua arguments
 /// * `name: string`         - The name of the task.
 /// * `description: string`  - A description of the task. (Optional)
-/// * `dependencies: table`  - A list of task names that the task depends on. (Optional)
+/// * `dependencies: table`  - A list of task names that the task depends on, along with any named
+///                            options. (Optional)
 /// * `func: function`       - A function that should be called when the task is run.
+///
+/// # Options
+/// * `pool: string`         - The pool that the task runs in.
+/// * `timeout: number`      - Number of seconds the task may run before it is stopped.
+/// * `retries: number`      - Number of times to run the task again if it fails.
+/// * `retry_delay: number`  - Number of seconds to wait before running the task again.
+/// * `watch: table`         - Glob patterns of additional files to watch in watch mode.
 fn create_task(runtime: Runtime) -> ScriptResult {
-    let name = runtime.state().check_string(1).to_string();
+    record_definition(&runtime, "task");
+
+    let scope = runtime.environment().scope();
+    let name = scope.task_name(runtime.state().check_string(1));
     let desc = get_next_description(runtime.clone());
     let mut func_index = 3;
+    let mut pool = None;
+    let mut timeout = None;
+    let mut retries = None;
+    let mut retry_delay = None;
+    let mut watch = Vec::new();
+    let mut params = Vec::new();
 
     // Get the list of dependencies if given.
     let deps = if runtime.state().type_of(2) == Some(lua::Type::Table) {
-        runtime.iter(2)
-            .map(|(_, value)| runtime.state().to_str_in_place(value).unwrap().to_string())
-            .collect()
+        params = try!(get_params_option(&runtime, 2));
+        pool = get_string_option(&runtime, 2, "pool");
+        watch = get_list_option(&runtime, 2, "watch")
+            .iter()
+            .map(|pattern| scope.file_path(pattern))
+            .collect();
+        timeout = get_duration_option(&runtime, 2, "timeout");
+        retries = get_number_option(&runtime, 2, "retries");
+        retry_delay = get_duration_option(&runtime, 2, "retry_delay");
+        get_list(&runtime, 2)
     } else {
         func_index -= 1;
         Vec::new()
@@ -141,30 +526,196 @@ fn create_task(runtime: Runtime) -> ScriptResult {
         None
     };
 
+    // Pushes the task function and its arguments onto the stack, and sets up the environment for
+    // it to run. Returns the number of arguments, and the scope to restore afterwards.
     let closure_env = runtime.clone();
     let later_name = name.clone();
-    let callback = func.map(|func| {
-        move || {
+    let prepare = func.map(|func| {
+        Rc::new(move || -> Result<(i32, Scope), Box<Error>> {
+            // Bind the arguments given on the command line to the task's parameters.
+            let values = match closure_env.environment().get_task(&name) {
+                Some(task) => try!(task.bind(&closure_env.environment().arguments(&name))),
+                None => Vec::new(),
+            };
+
             // Get the function reference onto the Lua stack.
             closure_env.state().raw_geti(lua::REGISTRYINDEX, func.value() as i64);
 
+            // Pass the arguments to the task function as a table.
+            closure_env.state().new_table();
+            for (name, value) in values {
+                runtime::push_value(&mut closure_env.state(), &value);
+                closure_env.state().set_field(-2, &name);
+            }
+            let positional = closure_env.environment().arguments(&name).positional;
+            for (i, value) in positional.iter().enumerate() {
+                closure_env.state().push_string(value);
+                closure_env.state().raw_seti(-2, i as i64 + 1);
+            }
+
+            // The function runs from the directory of the Rotefile defining the task.
+            let outer = closure_env.environment().set_scope(scope.clone());
+            closure_env.environment().set_current_task(name.clone());
+
+            Ok((1, outer))
+        })
+    });
+
+    let closure_env = runtime.clone();
+    let callback = prepare.clone().map(|prepare| {
+        move || {
             // Invoke the task function.
-            let name = name.clone();
-            closure_env.environment().set_current_task(name);
-            let result = closure_env.call(0, 0, 0).map(|_| ()).map_err(|e| e.into());
+            let (nargs, outer) = try!(prepare());
+            let result = closure_env.call(nargs, 0, 0).map(|_| ()).map_err(|e| e.into());
             closure_env.environment().clear_current_task();
+            closure_env.environment().set_scope(outer);
 
             result
         }
     });
 
-    runtime.environment().create_task(NamedTask::new(later_name, desc, deps, callback));
+    let mut task = NamedTask::new(later_name, desc, deps, callback);
+
+    // When run as a coroutine, the executor calls the function instead, and restores the
+    // environment once it returns.
+    if let Some(prepare) = prepare {
+        task.set_start(move || {
+            prepare().map(|(nargs, _)| {
+                PreparedCall {
+                    nargs: nargs,
+                }
+            })
+        });
+    }
+    task.pool = pool;
+    task.timeout = timeout;
+    task.retries = retries.map(|retries| retries.max(0.0) as u32).unwrap_or(0);
+    task.retry_delay = retry_delay.unwrap_or(Duration::from_secs(0));
+    task.watch = watch;
+    task.params = params;
+
+    runtime.environment().create_task(task);
+    Ok(0)
+}
+
+/// Loads another Rotefile, defining its tasks and rules under a namespace.
+///
+/// The included file runs relative to its own directory, and the names of its tasks are prefixed
+/// with the namespace, as in `api:build`.
+///
+/// # Lua arguments
+/// * `path: string`         - The directory containing the Rotefile, or the path of the file.
+/// * `namespace: string`    - The namespace to define tasks in. Defaults to the name of the
+///                            directory. (Optional)
+fn include(runtime: Runtime) -> ScriptResult {
+    let path = runtime.state().check_string(1).to_string();
+    let outer = runtime.environment().scope();
+
+    // Paths are relative to the directory of the including Rotefile.
+    let relative = runtime::normalize(&outer.file_path(&path));
+    let mut file = runtime.environment().directory().join(&relative);
+    let directory = if file.is_dir() {
+        file.push("Rotefile");
+        relative
+    } else {
+        match Path::new(&relative).parent() {
+            Some(parent) => parent.to_string_lossy().into_owned(),
+            None => String::new(),
+        }
+    };
+
+    // File names in the included Rotefile are rewritten relative to the root Rotefile, which
+    // only works for directories inside it.
+    let directory = if directory == "." { String::new() } else { directory };
+    if directory == ".." || directory.starts_with("../") {
+        return Err(format!("cannot include '{}' from outside the directory of the root Rotefile",
+                           path)
+            .into());
+    }
+
+    let namespace = if runtime.state().is_string(2) {
+        runtime.state().check_string(2).to_string()
+    } else {
+        match Path::new(&directory).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(format!("a namespace must be given to include '{}'", path).into()),
+        }
+    };
+
+    if !runtime.environment().mark_included(file.clone()) {
+        return Err(format!("'{}' has already been included", path).into());
+    }
+
+    let scope = Scope {
+        namespace: format!("{}{}:", outer.namespace, namespace),
+        directory: directory,
+    };
+    debug!("including '{}' as '{}'", file.to_string_lossy(), scope.namespace);
+
+    // Load the file in its own scope, making sure to restore the outer scope even on failure.
+    runtime.environment().set_scope(scope);
+    let result = runtime.load_file(&file);
+    runtime.environment().set_scope(outer);
+
+    try!(result);
+    Ok(0)
+}
+
+/// Replays a task or rule definition from a snapshot.
+///
+/// # Lua arguments
+/// * `kind: string`         - Either "task" or "rule".
+/// * `namespace: string`    - The namespace of the Rotefile that made the definition.
+/// * `directory: string`    - The directory of the Rotefile that made the definition.
+/// * `...`                  - The arguments the task or rule was defined with.
+fn define(runtime: Runtime) -> ScriptResult {
+    let kind = runtime.state().check_string(1).to_string();
+    let scope = Scope {
+        namespace: runtime.state().check_string(2).to_string(),
+        directory: runtime.state().check_string(3).to_string(),
+    };
+
+    // Leave only the arguments of the definition on the stack.
+    for _ in 0..3 {
+        runtime.state().remove(1);
+    }
+
+    let outer = runtime.environment().set_scope(scope);
+    let result = match kind.as_str() {
+        "task" => create_task(runtime.clone()),
+        "rule" => create_rule(runtime.clone()),
+        _ => Err(format!("unknown definition '{}'", kind).into()),
+    };
+    runtime.environment().set_scope(outer);
+
+    result
+}
+
+/// Defines a pool that limits how many of the tasks in it can run at once.
+///
+/// # Lua arguments
+/// * `name: string`         - The name of the pool.
+/// * `depth: number`        - The maximum number of tasks in the pool that can run at once.
+fn create_pool(runtime: Runtime) -> ScriptResult {
+    let name = runtime.state().check_string(1).to_string();
+    let depth = runtime.state().check_number(2);
+
+    if name == task::CONSOLE_POOL {
+        return Err(format!("the '{}' pool cannot be redefined", name).into());
+    }
+
+    if depth < 1.0 {
+        return Err(format!("pool '{}' must have a depth of at least 1", name).into());
+    }
+
+    runtime.environment().create_pool(name, depth as usize);
     Ok(0)
 }
 
 /// Gets the current working directory.
 fn current_dir(runtime: Runtime) -> ScriptResult {
-    Ok(env::current_dir()
+    Ok(runtime.environment()
+        .working_dir()
         .map(|dir| {
             runtime.state().push(dir.to_str());
             1
@@ -182,89 +733,166 @@ fn current_exe(runtime: Runtime) -> ScriptResult {
         .unwrap_or(0))
 }
 
+/// Waits for a child process started by a task function, and pushes the values that `reply` makes
+/// of its exit code.
+///
+/// A task running as a coroutine does not wait. Instead, the process is waited on in the
+/// background while the coroutine yields, so that other tasks can run in the meantime.
+fn wait_for<F>(runtime: &Runtime, process: Process, reply: F) -> ScriptResult
+    where F: FnOnce(io::Result<Option<i32>>) -> Reply + Send + 'static
+{
+    let coroutines = runtime.environment().coroutines();
+
+    if coroutines.can_defer(runtime.state().as_ptr()) {
+        coroutines.defer(process, reply);
+        return Ok(runtime::YIELD);
+    }
+
+    let values = try!(reply(process.wait(&runtime.environment().processes())));
+    for value in &values {
+        runtime::push_value(&mut runtime.state(), value);
+    }
+
+    Ok(values.len() as i32)
+}
+
 /// Executes a shell command with a given list of arguments.
 fn execute(runtime: Runtime) -> ScriptResult {
     // Create a command for the given program name.
-    let mut command = Command::new(runtime.state().check_string(1));
+    let program = runtime.state().check_string(1).to_string();
+    let mut command = Command::new(&program);
+    let mut template = vec![program.clone()];
+    let mut command_line = vec![program];
 
-    // Set the current directory.
-    if let Ok(dir) = env::current_dir() {
+    // Run the command in the directory of the Rotefile that defined it.
+    if let Ok(dir) = runtime.environment().working_dir() {
         command.current_dir(dir);
     }
 
     // For each other parameter given, add it as a shell argument.
     for i in 2..runtime.state().get_top()+1 {
         // Expand each argument as we go.
-        command.arg(expand_string(runtime.state().check_string(i), runtime.clone()));
+        let raw = runtime.state().check_string(i).to_string();
+        let arg = expand_string(&raw, runtime.clone());
+        command.arg(&arg);
+        template.push(raw);
+        command_line.push(arg);
     }
 
-    // Spawn the command, capturing its status.
-    command.status().map_err(|e| {
-        format!("failed to execute process: {}", e).into()
-    }).and_then(|status| {
-        let status = status.code().unwrap_or(1);
+    runtime.environment().record_command(CommandLine {
+        template: template,
+        args: command_line.clone(),
+    });
+
+    let task = runtime.environment().current_task().unwrap_or(String::new());
+    let output = runtime.environment().output();
+    output.capture(&mut command);
+
+    // Record the command in the timeline once it exits, and fail if it was not successful.
+    let trace = runtime.environment().trace();
+    let start = Instant::now();
+    let task_name = task.clone();
+    let reply = move |status: io::Result<Option<i32>>| {
+        if let Some(trace) = trace {
+            let mut args = JsonValue::new_object();
+            args["task"] = task_name.as_str().into();
+            match status {
+                Ok(Some(code)) => args["status"] = format!("exit code: {}", code).into(),
+                Ok(None) => args["status"] = "killed by signal".into(),
+                Err(ref e) => args["error"] = e.to_string().into(),
+            }
+
+            trace.span(command_line.join(" "), "process", start, Instant::now(), args);
+        }
+
+        let status = try!(status.map_err(|e| format!("failed to execute process: {}", e)));
+        let status = status.unwrap_or(1);
 
         if status > 0 {
-            Err("command returned nonzero exit code".into())
+            Err("command returned nonzero exit code".to_string())
         } else {
-            runtime.state().push_number(status as f64);
-            Ok(1)
+            Ok(vec![Value::Number(status as f64)])
         }
-    })
+    };
+
+    // Spawn the command, and pass its output along as it runs.
+    let mut process = match runtime.environment().processes().spawn(&task, &mut command) {
+        Ok(child) => Process::new(child),
+        Err(e) => return Err(reply(Err(e)).unwrap_err().into()),
+    };
+    output.forward(&task, &mut process);
+
+    wait_for(&runtime, process, reply)
 }
 
 /// Pipes a string into a shell command with a given list of arguments.
+///
+/// Returns the standard output and error of the command as strings, which may hold any bytes, and
+/// its exit code.
 fn pipe(runtime: Runtime) -> ScriptResult {
     // Create a command for the given program name.
-    let mut command = Command::new(runtime.state().check_string(2));
+    let program = runtime.state().check_string(2).to_string();
+    let mut command = Command::new(&program);
+    let mut template = vec![program.clone()];
+    let mut command_line = vec![program];
 
-    // Set the current directory.
-    if let Ok(dir) = env::current_dir() {
+    // Run the command in the directory of the Rotefile that defined it.
+    if let Ok(dir) = runtime.environment().working_dir() {
         command.current_dir(dir);
     }
 
     // For each other parameter given, add it as a shell argument.
     for i in 3..runtime.state().get_top()+1 {
         // Expand each argument as we go.
-        command.arg(expand_string(runtime.state().check_string(i), runtime.clone()));
+        let raw = runtime.state().check_string(i).to_string();
+        let arg = expand_string(&raw, runtime.clone());
+        command.arg(&arg);
+        template.push(raw);
+        command_line.push(arg);
     }
 
+    runtime.environment().record_command(CommandLine {
+        template: template,
+        args: command_line,
+    });
+
     // Get the input buffer string, if given.
     let input = if runtime.state().type_of(1) == Some(lua::Type::Nil) {
         command.stdin(Stdio::null());
         None
     } else {
         command.stdin(Stdio::piped());
-        Some(runtime.state().check_string(1).to_string())
+        Some(runtime.state().check_string(1).as_bytes().to_vec())
     };
 
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
 
     // Start running the command process.
-    let mut child = match command.spawn() {
-        Ok(child) => child,
+    let task = runtime.environment().current_task().unwrap_or(String::new());
+    let mut process = match runtime.environment().processes().spawn(&task, &mut command) {
+        Ok(child) => Process::new(child),
         Err(e) => return Err(format!("failed to execute process: {}", e).into()),
     };
 
-    // Write the input string to the pipe if given.
+    // Write the input string to the pipe if given, and collect the output.
     if let Some(input) = input {
-        if let Err(e) = child.stdin.as_mut().unwrap().write_all(input.as_bytes()) {
-            return Err(format!("failed to execute process: {}", e).into());
-        }
+        process.set_input(input);
     }
 
-    // Wait for the program to finish and collect the output.
-    child.wait_with_output().map_err(|e| {
-        format!("failed to execute process: {}", e).into()
-    }).and_then(|output| {
-        unsafe {
-            runtime.state().push_string(str::from_utf8_unchecked(&output.stdout));
-            runtime.state().push_string(str::from_utf8_unchecked(&output.stderr));
-        }
-        runtime.state().push_number(output.status.code().unwrap_or(1) as f64);
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+    process.set_stdout(Box::new(stdout.clone()));
+    process.set_stderr(Box::new(stderr.clone()));
+
+    wait_for(&runtime, process, move |status| {
+        let status = try!(status.map_err(|e| format!("failed to execute process: {}", e)));
 
-        Ok(3)
+        Ok(vec![
+            Value::Bytes(mem::replace(&mut *stdout.lock().unwrap(), Vec::new())),
+            Value::Bytes(mem::replace(&mut *stderr.lock().unwrap(), Vec::new())),
+            Value::Number(status.unwrap_or(1) as f64),
+        ])
     })
 }
 
@@ -323,7 +951,7 @@ fn glob(runtime: Runtime) -> ScriptResult {
     let mut full_path = PathBuf::from(pattern);
     if full_path.is_relative() {
         let path = full_path;
-        full_path = env::current_dir().unwrap();
+        full_path = runtime.environment().working_dir().unwrap();
         full_path.push(&path);
     }
 
@@ -427,7 +1055,8 @@ fn print(runtime: Runtime) -> ScriptResult {
         runtime.state().pop(1);
 
         let string = expand_string(&string, runtime.clone());
-        println!("{}", string);
+        let task = runtime.environment().current_task().unwrap_or(String::new());
+        runtime.environment().output().write(&task, &string);
     }
 
     Ok(0)
@@ -441,6 +1070,12 @@ fn set_default_task(runtime: Runtime) -> ScriptResult {
     // Get the task name as the first argument.
     let name = runtime.state().check_string(1).to_string();
 
+    // Only the root Rotefile decides what runs by default.
+    if !runtime.environment().scope().namespace.is_empty() {
+        debug!("ignoring default task '{}' set in an included Rotefile", name);
+        return Ok(0);
+    }
+
     // Set the default task to the given name.
     runtime.environment().set_default_task(name);
 
@@ -466,11 +1101,69 @@ fn version(runtime: Runtime) -> ScriptResult {
 }
 
 
+/// Starts recording the tasks and rules that are defined, so that a snapshot of them can be taken
+/// with `save_snapshot()`.
+pub fn start_snapshot(runtime: &Runtime) {
+    runtime.reg_get("rote.snapshot");
+    runtime.state().get_field(-1, "start");
+
+    if let Err(e) = runtime.call(0, 0, 0) {
+        warn!("failed to start recording definitions: {}", e);
+        runtime.state().pop(1);
+    }
+
+    runtime.state().pop(1);
+}
+
+/// Takes a snapshot of the tasks and rules defined since `start_snapshot()` was called, along with
+/// the globals they use.
+///
+/// The snapshot is Lua source code, which can be passed to `restore_snapshot()` to define the same
+/// tasks and rules in another runtime without evaluating the Rotefile again.
+pub fn save_snapshot(runtime: &Runtime) -> Result<String, Box<Error>> {
+    runtime.reg_get("rote.snapshot");
+    runtime.state().get_field(-1, "save");
+
+    let result = runtime.call(0, 1, 0)
+        .map(|_| runtime.state().to_str(-1).unwrap_or("").to_string());
+    runtime.state().pop(2);
+
+    result
+}
+
+/// Defines the tasks and rules in a snapshot taken by `save_snapshot()`.
+pub fn restore_snapshot(runtime: &Runtime, snapshot: &str) -> Result<(), Box<Error>> {
+    runtime.reg_get("rote.snapshot");
+    runtime.state().get_field(-1, "restore");
+    runtime.state().push_string(snapshot);
+    runtime.push_fn(define);
+
+    let result = runtime.call(2, 0, 0);
+    if result.is_err() {
+        runtime.state().pop(1);
+    }
+    runtime.state().pop(1);
+    try!(result);
+
+    // Now that every task is known, dependencies can be resolved.
+    runtime.environment().qualify_dependencies()
+}
+
+
 /// Makes the standard Rote module functions available in the runtime.
 pub fn load(runtime: Runtime) {
+    // Store the fingerprint function in the registry for later use.
+    runtime.eval(FINGERPRINT_SOURCE).unwrap();
+    runtime.reg_set("rote.fingerprint");
+
+    // Store the snapshot module in the registry for later use.
+    runtime.eval(SNAPSHOT_SOURCE).unwrap();
+    runtime.reg_set("rote.snapshot");
+
     // Load the module functions.
     runtime.load_lib(&[
         ("change_dir", change_dir),
+        ("create_pool", create_pool),
         ("create_rule", create_rule),
         ("create_task", create_task),
         ("current_dir", current_dir),
@@ -480,6 +1173,7 @@ pub fn load(runtime: Runtime) {
         ("expand", expand),
         ("export", export),
         ("glob", glob),
+        ("include", include),
         ("merge", merge),
         ("pipe", pipe),
         ("print", print),
@@ -495,7 +1189,9 @@ pub fn load(runtime: Runtime) {
     runtime.register_fn("exec", execute);
     runtime.register_fn("export", export);
     runtime.register_fn("glob", glob);
+    runtime.register_fn("include", include);
     runtime.register_fn("pipe", pipe);
+    runtime.register_fn("pool", create_pool);
     runtime.register_fn("print", print);
     runtime.register_fn("rule", create_rule);
     runtime.register_fn("task", create_task);
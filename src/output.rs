@@ -0,0 +1,271 @@
+use reaper::{Process, Sink};
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::mem;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+
+/// Output collected for a task in grouped mode.
+pub type Buffer = Arc<Mutex<Collected>>;
+
+/// The standard output and error collected for a task, kept apart so that each can be printed to
+/// where it belongs.
+#[derive(Default)]
+pub struct Collected {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// How the output of tasks is displayed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Output is written straight to the terminal as it is produced.
+    Passthrough,
+
+    /// The output of each task is collected and printed all at once when the task finishes, under
+    /// a header with the task name.
+    Grouped,
+
+    /// Each line of output is printed as soon as it is produced, prefixed with the task name.
+    Prefixed,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "passthrough" => Ok(Mode::Passthrough),
+            "grouped" => Ok(Mode::Grouped),
+            "prefixed" => Ok(Mode::Prefixed),
+            _ => Err(format!("unknown output mode '{}'", s)),
+        }
+    }
+}
+
+/// Handles the output of the tasks run in a single thread, including the output of the commands
+/// they execute.
+pub struct Output {
+    mode: Cell<Mode>,
+
+    /// Output collected for the current task in grouped mode.
+    buffer: RefCell<Buffer>,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            mode: Cell::new(Mode::Passthrough),
+            buffer: RefCell::new(Buffer::default()),
+        }
+    }
+
+    /// Gets the output mode.
+    pub fn mode(&self) -> Mode {
+        self.mode.get()
+    }
+
+    /// Sets the output mode.
+    pub fn set_mode(&self, mode: Mode) {
+        self.mode.set(mode);
+    }
+
+    /// Replaces the buffer that output is collected in for the current task, returning the old one.
+    ///
+    /// Used to give each task its own buffer when several tasks take turns running in one thread.
+    pub fn swap_buffer(&self, buffer: Buffer) -> Buffer {
+        mem::replace(&mut *self.buffer.borrow_mut(), buffer)
+    }
+
+    /// Writes text printed by a task.
+    pub fn write(&self, task: &str, text: &str) {
+        match self.mode() {
+            Mode::Passthrough => println!("{}", text),
+            Mode::Grouped => {
+                let buffer = self.buffer.borrow();
+                let mut buffer = buffer.lock().unwrap();
+                buffer.stdout.extend_from_slice(text.as_bytes());
+                buffer.stdout.push(b'\n');
+            }
+            Mode::Prefixed => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+
+                for line in text.lines() {
+                    let _ = writeln!(stdout, "[{}] {}", task, line);
+                }
+            }
+        }
+    }
+
+    /// Prepares a command so that its output can be handled by `forward()`.
+    pub fn capture(&self, command: &mut Command) {
+        if self.mode() != Mode::Passthrough {
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+        }
+    }
+
+    /// Passes the output of a child process prepared with `capture()` along as it is read.
+    pub fn forward(&self, task: &str, process: &mut Process) {
+        process.set_stdout(self.sink(task, false));
+        process.set_stderr(self.sink(task, true));
+    }
+
+    /// Prints the output collected for a task in grouped mode, if there is any.
+    ///
+    /// Output collected while no task was running, such as while the Rotefile was loaded, is
+    /// printed without a header.
+    pub fn flush(&self, task: &str) {
+        let buffer = mem::replace(&mut *self.buffer.borrow().lock().unwrap(),
+                                  Collected::default());
+
+        if buffer.stdout.is_empty() && buffer.stderr.is_empty() {
+            return;
+        }
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+
+        if !task.is_empty() {
+            let _ = writeln!(stdout, "=== {} ===", task);
+        }
+
+        let _ = write_all(&mut stdout, &buffer.stdout);
+        let _ = stdout.flush();
+        let _ = write_all(&mut stderr, &buffer.stderr);
+    }
+
+    /// Creates a sink for the standard output or error of a command run by a task.
+    fn sink(&self, task: &str, is_stderr: bool) -> Box<Sink> {
+        match self.mode() {
+            Mode::Grouped => {
+                Box::new(Collector {
+                    buffer: self.buffer.borrow().clone(),
+                    is_stderr: is_stderr,
+                })
+            }
+            mode => {
+                Box::new(Lines {
+                    prefix: if mode == Mode::Prefixed {
+                        format!("[{}] ", task)
+                    } else {
+                        String::new()
+                    },
+                    is_stderr: is_stderr,
+                    line: Vec::new(),
+                })
+            }
+        }
+    }
+}
+
+/// Collects the output of a command in the buffer of a task, for grouped mode.
+struct Collector {
+    buffer: Buffer,
+    is_stderr: bool,
+}
+
+impl Sink for Collector {
+    fn write(&mut self, data: &[u8]) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        if self.is_stderr {
+            buffer.stderr.extend_from_slice(data);
+        } else {
+            buffer.stdout.extend_from_slice(data);
+        }
+    }
+}
+
+/// Prints the output of a command a line at a time, with each line prefixed.
+struct Lines {
+    prefix: String,
+    is_stderr: bool,
+
+    /// The part of the current line read so far.
+    line: Vec<u8>,
+}
+
+impl Lines {
+    /// Writes whole lines at a time so that lines from different tasks are never mixed together.
+    fn print(&self, line: &[u8]) {
+        let _ = if self.is_stderr {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            stderr.write_all(self.prefix.as_bytes()).and_then(|_| stderr.write_all(line))
+        } else {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(self.prefix.as_bytes()).and_then(|_| stdout.write_all(line))
+        };
+    }
+}
+
+impl Sink for Lines {
+    fn write(&mut self, data: &[u8]) {
+        self.line.extend_from_slice(data);
+
+        while let Some(end) = self.line.iter().position(|&c| c == b'\n') {
+            let rest = self.line.split_off(end + 1);
+            self.print(&self.line);
+            self.line = rest;
+        }
+    }
+
+    fn close(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            self.print(&self.line);
+            self.line.clear();
+        }
+    }
+}
+
+/// Writes collected output, ending it with a newline if it does not have one.
+fn write_all<W: Write>(writer: &mut W, output: &[u8]) -> io::Result<()> {
+    if output.is_empty() {
+        return Ok(());
+    }
+
+    try!(writer.write_all(output));
+    if !output.ends_with(b"\n") {
+        try!(writer.write_all(b"\n"));
+    }
+
+    Ok(())
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use processes::Processes;
+    use reaper::Process;
+    use std::process::Command;
+    use super::*;
+
+    #[test]
+    fn grouped_output_keeps_stderr_apart() {
+        let output = Output::new();
+        output.set_mode(Mode::Grouped);
+        let buffer = Buffer::default();
+        output.swap_buffer(buffer.clone());
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2");
+        output.capture(&mut command);
+
+        let mut process = Process::new(command.spawn().unwrap());
+        output.forward("test", &mut process);
+        assert_eq!(process.wait(&Processes::new()).unwrap(), Some(0));
+        output.write("test", "printed");
+
+        let buffer = buffer.lock().unwrap();
+        assert_eq!(buffer.stdout, b"out\nprinted\n");
+        assert_eq!(buffer.stderr, b"err\n");
+    }
+}
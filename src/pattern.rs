@@ -0,0 +1,350 @@
+use regex::{self, Regex};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+
+/// A file name pattern that rules use to match output files.
+///
+/// Patterns are written using `%` wildcards. A single `%` matches any part of a file name, called
+/// the *stem*, and `%{name}` matches a stem and gives it a name, as in `build/%{arch}/%{name}.o`.
+/// Patterns can also be given as regular expressions, where named groups act as named stems.
+pub struct Pattern {
+    /// The pattern as it was written.
+    source: String,
+
+    /// The compiled expression, or `None` if the pattern is a literal file name.
+    regex: Option<Regex>,
+}
+
+impl Pattern {
+    /// Parses a pattern that uses `%` wildcards.
+    pub fn new<S: Into<String>>(source: S) -> Result<Pattern, Box<Error>> {
+        let source = source.into();
+
+        if !source.contains('%') {
+            return Ok(Pattern {
+                source: source,
+                regex: None,
+            });
+        }
+
+        // Translate the pattern into an equivalent regular expression.
+        let mut expression = String::from("^");
+        let mut names = Vec::new();
+        let mut rest = source.as_str();
+
+        while let Some(index) = rest.find('%') {
+            expression.push_str(&regex::quote(&rest[..index]));
+            rest = &rest[index + 1..];
+
+            if rest.starts_with('{') {
+                let end = match rest.find('}') {
+                    Some(end) => end,
+                    None => return Err(format!("unclosed stem in pattern '{}'", source).into()),
+                };
+
+                let name = &rest[1..end];
+                if !is_stem_name(name) {
+                    return Err(format!("invalid stem name '{}' in pattern '{}'", name, source)
+                        .into());
+                }
+                if names.contains(&name) {
+                    return Err(format!("stem '{}' appears more than once in pattern '{}'",
+                                       name,
+                                       source)
+                        .into());
+                }
+                names.push(name);
+
+                expression.push_str(&format!("(?P<{}>.*)", name));
+                rest = &rest[end + 1..];
+            } else {
+                expression.push_str("(.*)");
+            }
+        }
+
+        expression.push_str(&regex::quote(rest));
+        expression.push('$');
+
+        Ok(Pattern {
+            regex: Some(try!(Regex::new(&expression))),
+            source: source,
+        })
+    }
+
+    /// Parses a pattern written as a regular expression.
+    ///
+    /// The expression must match an entire file name.
+    pub fn regex<S: Into<String>>(source: S) -> Result<Pattern, Box<Error>> {
+        let source = source.into();
+        let regex = try!(Regex::new(&format!("^(?:{})$", source)));
+
+        Ok(Pattern {
+            source: source,
+            regex: Some(regex),
+        })
+    }
+
+    /// Parses a pattern written as a regular expression that matches file names inside a
+    /// directory.
+    ///
+    /// The expression must match the entire part of a file name that follows the directory. A `^`
+    /// at the start or a `$` at the end of the expression is allowed, and anchors it to that part.
+    pub fn regex_in<S: AsRef<str>>(directory: &str, source: S) -> Result<Pattern, Box<Error>> {
+        let mut source = source.as_ref();
+
+        if source.starts_with('^') {
+            source = &source[1..];
+        }
+
+        // A `$` escaped with a backslash is a literal dollar sign, not an anchor.
+        if source.ends_with('$') {
+            let end = source.len() - 1;
+            let escapes = source[..end].chars().rev().take_while(|&c| c == '\\').count();
+            if escapes % 2 == 0 {
+                source = &source[..end];
+            }
+        }
+
+        Pattern::regex(format!("{}(?:{})", regex::quote(&format!("{}/", directory)), source))
+    }
+
+    /// Gets the pattern as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Checks if the pattern contains any wildcards.
+    pub fn is_literal(&self) -> bool {
+        self.regex.is_none()
+    }
+
+    /// Checks that every stem that a template refers to with `%{...}` is captured by the pattern,
+    /// so that expanding the template with the stems of a match never leaves a stem out.
+    pub fn check_template<S: AsRef<str>>(&self, template: S) -> Result<(), Box<Error>> {
+        let regex = match self.regex {
+            // Templates are left alone when nothing is captured.
+            Some(ref regex) if regex.captures_len() > 1 => regex,
+            _ => return Ok(()),
+        };
+
+        let mut rest = template.as_ref();
+
+        while let Some(index) = rest.find('%') {
+            rest = &rest[index + 1..];
+
+            if let (true, Some(end)) = (rest.starts_with('{'), rest.find('}')) {
+                let key = &rest[1..end];
+                let captured = match key.parse::<usize>() {
+                    Ok(n) => n > 0 && n < regex.captures_len(),
+                    Err(_) => regex.capture_names().any(|name| name == Some(key)),
+                };
+
+                if !captured {
+                    return Err(format!("stem '{}' in '{}' is not captured by pattern '{}'",
+                                       key,
+                                       template.as_ref(),
+                                       self.source)
+                        .into());
+                }
+
+                rest = &rest[end + 1..];
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Matches a file name against the pattern, returning the captured stems if it matches.
+    pub fn captures<S: AsRef<str>>(&self, name: S) -> Option<Captures> {
+        let name = name.as_ref();
+
+        match self.regex {
+            Some(ref regex) => {
+                regex.captures(name).map(|caps| {
+                    Captures {
+                        positional: (1..caps.len())
+                            .map(|i| caps.at(i).unwrap_or("").to_string())
+                            .collect(),
+                        named: caps.iter_named()
+                            .map(|(key, value)| (key.to_string(), value.unwrap_or("").to_string()))
+                            .collect(),
+                    }
+                })
+            }
+            None if self.source == name => Some(Captures::default()),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// The stems captured when a file name is matched against a pattern.
+#[derive(Clone, Default)]
+pub struct Captures {
+    /// Every captured stem, in the order they appear in the pattern.
+    pub positional: Vec<String>,
+
+    /// Captured stems that have a name.
+    pub named: HashMap<String, String>,
+}
+
+impl Captures {
+    /// Gets the total length of all captured stems.
+    pub fn stem_length(&self) -> usize {
+        self.positional.iter().fold(0, |length, stem| length + stem.len())
+    }
+
+    /// Expands a template using the captured stems.
+    ///
+    /// A `%` in the template is replaced with the first stem, `%{name}` is replaced with the stem
+    /// with the given name, and `%{n}` is replaced with the `n`th stem. If nothing was captured,
+    /// as for a literal file name, the template is returned unchanged.
+    pub fn expand<S: AsRef<str>>(&self, template: S) -> String {
+        if self.positional.is_empty() && self.named.is_empty() {
+            return template.as_ref().to_string();
+        }
+
+        let mut result = String::new();
+        let mut rest = template.as_ref();
+
+        while let Some(index) = rest.find('%') {
+            result.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            if let (true, Some(end)) = (rest.starts_with('{'), rest.find('}')) {
+                let key = &rest[1..end];
+                let value = match key.parse::<usize>() {
+                    Ok(n) if n > 0 => self.positional.get(n - 1),
+                    _ => self.named.get(key),
+                };
+
+                result.push_str(value.map(|s| s.as_str()).unwrap_or(""));
+                rest = &rest[end + 1..];
+            } else {
+                result.push_str(self.positional.first().map(|s| s.as_str()).unwrap_or(""));
+            }
+        }
+
+        result.push_str(rest);
+        result
+    }
+}
+
+/// Checks if a stem name can be used as the name of a group in a regular expression.
+fn is_stem_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c == '_' || c.is_alphabetic() => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c == '_' || c.is_alphanumeric())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_patterns_match_exactly() {
+        let pattern = Pattern::new("build/app").unwrap();
+
+        assert!(pattern.is_literal());
+        assert!(pattern.captures("build/app").is_some());
+        assert!(pattern.captures("build/app.o").is_none());
+    }
+
+    #[test]
+    fn wildcards_capture_stems() {
+        let pattern = Pattern::new("build/%{arch}/%.o").unwrap();
+        let captures = pattern.captures("build/x86/main.o").unwrap();
+
+        assert_eq!(captures.positional, vec!["x86", "main"]);
+        assert_eq!(captures.named["arch"], "x86");
+        assert_eq!(captures.stem_length(), 7);
+        assert!(pattern.captures("src/x86/main.o").is_none());
+    }
+
+    #[test]
+    fn special_characters_are_literal() {
+        let pattern = Pattern::new("%.tar.gz").unwrap();
+
+        assert!(pattern.captures("a.tar.gz").is_some());
+        assert!(pattern.captures("a.tarxgz").is_none());
+    }
+
+    #[test]
+    fn duplicate_stem_names_are_rejected() {
+        let error = Pattern::new("%{name}/%{name}.o").err().unwrap();
+
+        assert_eq!(error.to_string(),
+                   "stem 'name' appears more than once in pattern '%{name}/%{name}.o'");
+    }
+
+    #[test]
+    fn invalid_stems_are_rejected() {
+        assert!(Pattern::new("%{a-b}.o").is_err());
+        assert!(Pattern::new("%{}.o").is_err());
+        assert!(Pattern::new("%{name.o").is_err());
+    }
+
+    #[test]
+    fn regex_patterns_match_whole_names() {
+        let pattern = Pattern::regex(r"(?P<name>\w+)\.o").unwrap();
+
+        assert_eq!(pattern.captures("main.o").unwrap().named["name"], "main");
+        assert!(pattern.captures("src/main.o").is_none());
+    }
+
+    #[test]
+    fn regex_patterns_in_directories_allow_anchors() {
+        let pattern = Pattern::regex_in("api", r"^(?P<name>\w+)\.o$").unwrap();
+        assert_eq!(pattern.captures("api/main.o").unwrap().named["name"], "main");
+        assert!(pattern.captures("main.o").is_none());
+
+        let pattern = Pattern::regex_in("api", r"cost\$").unwrap();
+        assert!(pattern.captures("api/cost$").is_some());
+        assert!(pattern.captures("api/cost").is_none());
+    }
+
+    #[test]
+    fn expand_replaces_stems() {
+        let captures = Pattern::new("%{dir}/%.o").unwrap().captures("src/main.o").unwrap();
+
+        assert_eq!(captures.expand("%.c"), "src.c");
+        assert_eq!(captures.expand("%{dir}/%{2}.c"), "src/main.c");
+        assert_eq!(captures.expand("%{missing}.c"), ".c");
+    }
+
+    #[test]
+    fn templates_may_only_refer_to_captured_stems() {
+        let pattern = Pattern::new("%{dir}/%.o").unwrap();
+
+        assert!(pattern.check_template("%{dir}/%{2}.c").is_ok());
+        assert!(pattern.check_template("%.c 100%").is_ok());
+        assert!(pattern.check_template("%{3}.c").is_err());
+        assert!(pattern.check_template("%{0}.c").is_err());
+        assert_eq!(pattern.check_template("src/%{missing}.c").err().unwrap().to_string(),
+                   "stem 'missing' in 'src/%{missing}.c' is not captured by pattern '%{dir}/%.o'");
+
+        assert!(Pattern::regex(r"(?P<name>\w+)\.o").unwrap().check_template("%{name}.c").is_ok());
+        assert!(Pattern::new("app").unwrap().check_template("%{name}.c").is_ok());
+    }
+
+    #[test]
+    fn expand_without_captures_leaves_templates_alone() {
+        let captures = Pattern::new("app").unwrap().captures("app").unwrap();
+
+        assert_eq!(captures.expand("100%.txt"), "100%.txt");
+        assert_eq!(captures.expand("%{name}.c"), "%{name}.c");
+    }
+}
@@ -0,0 +1,421 @@
+use libc;
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::mem;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+#[cfg(unix)]
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+use std::time::Duration;
+use timer::Timer;
+
+
+/// Set once an interrupt or termination signal has been received.
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The signal that interrupted the program.
+static SIGNAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// Installs handlers for `SIGINT` and `SIGTERM`.
+///
+/// The handlers only record that a signal was received; it is up to the runner to check
+/// `interrupted()` and stop any running tasks. If a second signal arrives before the program has
+/// stopped, such as a task stuck in Lua code that never checks, the program is terminated by the
+/// signal right away.
+#[cfg(unix)]
+pub fn install_handlers() {
+    extern "C" fn handle(signal: libc::c_int) {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+            return;
+        }
+
+        SIGNAL.store(signal as usize, Ordering::SeqCst);
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        libc::sigaction(libc::SIGINT, &action, ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_handlers() {}
+
+/// Checks if the program has been interrupted by a signal.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Gets the signal that interrupted the program, if any.
+pub fn signal() -> Option<i32> {
+    if interrupted() {
+        Some(SIGNAL.load(Ordering::SeqCst) as i32)
+    } else {
+        None
+    }
+}
+
+/// A registry of child processes started by tasks.
+///
+/// Every child is started in a process group of its own, so that the group can be signalled as a
+/// whole when the program is interrupted, including any processes the child started itself. Only
+/// the children of tasks marked as running in the foreground stay in the process group of the
+/// program, so that they can use the terminal.
+pub struct Processes {
+    /// Running children, keyed by process ID.
+    children: Mutex<HashMap<u32, ChildInfo>>,
+
+    /// Tasks whose processes have been killed. These tasks may not start any new processes.
+    killed: Mutex<HashSet<String>>,
+
+    /// Tasks whose processes stay in the foreground process group.
+    foreground: Mutex<HashSet<String>>,
+}
+
+/// Stops a task if it runs for longer than a timeout.
+///
+/// Once the timeout passes, the child processes that the task started are killed, which makes the
+/// commands that the task is waiting on fail. A task that is busy running Lua code instead is
+/// stopped by its interrupt, if it has one.
+pub struct Deadline {
+    /// Whichever of the task or the timer finishes first decides the outcome.
+    state: Arc<Mutex<DeadlineState>>,
+
+    /// ID of the timer callback that stops the task.
+    callback: usize,
+
+    timeout: Duration,
+    task: String,
+    processes: Arc<Processes>,
+    timer: Arc<Timer>,
+}
+
+/// Stops a task that is busy running code of its own. Called from the timer thread.
+pub type Interrupt = Box<Fn() + Send>;
+
+struct DeadlineState {
+    status: usize,
+    interrupt: Option<Interrupt>,
+}
+
+const RUNNING: usize = 0;
+const FINISHED: usize = 1;
+const TIMED_OUT: usize = 2;
+
+/// A running child process.
+struct ChildInfo {
+    /// Name of the task that started the process.
+    task: String,
+
+    /// Indicates if the child leads its own process group.
+    grouped: bool,
+}
+
+impl Processes {
+    pub fn new() -> Processes {
+        Processes {
+            children: Mutex::new(HashMap::new()),
+            killed: Mutex::new(HashSet::new()),
+            foreground: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Spawns a command for a task and adds it to the registry.
+    ///
+    /// No new processes are started once the program has been interrupted, or once the task's
+    /// processes have been killed. The child must be removed again by waiting on it with `wait()`,
+    /// or with `remove()` once it has been waited on some other way.
+    pub fn spawn(&self, task: &str, command: &mut Command) -> io::Result<Child> {
+        if interrupted() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+        }
+
+        if self.is_killed(task) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "task was stopped"));
+        }
+
+        let grouped = !self.foreground.lock().unwrap().contains(task) &&
+                      set_process_group(command);
+        let child = try!(command.spawn());
+
+        self.children.lock().unwrap().insert(child.id(),
+                                             ChildInfo {
+                                                 task: task.to_string(),
+                                                 grouped: grouped,
+                                             });
+
+        // The signal may have arrived while the child was being started.
+        if let Some(signal) = signal() {
+            send_signal(child.id(), grouped, signal);
+        }
+
+        Ok(child)
+    }
+
+    /// Waits for a child to exit and removes it from the registry.
+    pub fn wait(&self, child: &mut Child) -> io::Result<ExitStatus> {
+        let result = child.wait();
+        self.remove(child.id());
+        result
+    }
+
+    /// Removes a child that has exited from the registry.
+    pub fn remove(&self, id: u32) {
+        self.children.lock().unwrap().remove(&id);
+    }
+
+    /// Gets the number of running children.
+    pub fn len(&self) -> usize {
+        self.children.lock().unwrap().len()
+    }
+
+    /// Sends a signal to every running child and its process group.
+    pub fn signal_all(&self, signal: i32) {
+        for (&id, child) in self.children.lock().unwrap().iter() {
+            trace!("sending signal {} to process {}", signal, id);
+            send_signal(id, child.grouped, signal);
+        }
+    }
+
+    /// Forcefully kills every running child and its process group.
+    #[cfg(unix)]
+    pub fn kill_all(&self) {
+        self.signal_all(libc::SIGKILL);
+    }
+
+    #[cfg(not(unix))]
+    pub fn kill_all(&self) {}
+
+    /// Forcefully kills every running child started by a task, and prevents the task from starting
+    /// any more processes until `resume()` is called.
+    #[cfg(unix)]
+    pub fn kill_task(&self, task: &str) {
+        self.killed.lock().unwrap().insert(task.to_string());
+
+        for (&id, child) in self.children.lock().unwrap().iter() {
+            if child.task == task {
+                trace!("killing process {} of task '{}'", id, task);
+                send_signal(id, child.grouped, libc::SIGKILL);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn kill_task(&self, task: &str) {
+        self.killed.lock().unwrap().insert(task.to_string());
+    }
+
+    /// Checks if the processes of a task have been killed.
+    pub fn is_killed(&self, task: &str) -> bool {
+        self.killed.lock().unwrap().contains(task)
+    }
+
+    /// Allows a task whose processes were killed to start processes again.
+    pub fn resume(&self, task: &str) {
+        self.killed.lock().unwrap().remove(task);
+    }
+
+    /// Sets whether the processes that a task starts stay in the foreground process group, such
+    /// as for tasks in the console pool that need to read from the terminal.
+    pub fn set_foreground(&self, task: &str, foreground: bool) {
+        if foreground {
+            self.foreground.lock().unwrap().insert(task.to_string());
+        } else {
+            self.foreground.lock().unwrap().remove(task);
+        }
+    }
+}
+
+/// Makes a command start its child in a new process group. Returns true if supported.
+///
+/// The child moves itself into the group before running the program, so that no process it starts
+/// can be left behind in the group of the parent.
+#[cfg(unix)]
+fn set_process_group(command: &mut Command) -> bool {
+    command.before_exec(|| {
+        if unsafe { libc::setpgid(0, 0) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    });
+
+    true
+}
+
+#[cfg(not(unix))]
+fn set_process_group(_: &mut Command) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn send_signal(id: u32, grouped: bool, signal: i32) {
+    // A negative process ID signals every process in the group.
+    let target = if grouped {
+        -(id as libc::pid_t)
+    } else {
+        id as libc::pid_t
+    };
+
+    unsafe {
+        libc::kill(target, signal);
+    }
+}
+
+#[cfg(not(unix))]
+fn send_signal(_: u32, _: bool, _: i32) {}
+
+impl Deadline {
+    /// Starts the timer for a task that is about to run.
+    pub fn start(task: &str,
+                 timeout: Duration,
+                 processes: &Arc<Processes>,
+                 timer: &Arc<Timer>)
+                 -> Deadline {
+        let state = Arc::new(Mutex::new(DeadlineState {
+            status: RUNNING,
+            interrupt: None,
+        }));
+
+        let callback = {
+            let state = state.clone();
+            let processes = processes.clone();
+            let name = task.to_string();
+
+            timer.schedule(timeout, move || {
+                let mut state = state.lock().unwrap();
+                if state.status != RUNNING {
+                    return;
+                }
+
+                debug!("task '{}' timed out; killing its processes", name);
+                state.status = TIMED_OUT;
+                processes.kill_task(&name);
+
+                if let Some(ref interrupt) = state.interrupt {
+                    interrupt();
+                }
+            })
+        };
+
+        Deadline {
+            state: state,
+            callback: callback,
+            timeout: timeout,
+            task: task.to_string(),
+            processes: processes.clone(),
+            timer: timer.clone(),
+        }
+    }
+
+    /// Sets how to stop the task if it is busy when the timeout passes.
+    ///
+    /// The interrupt is never called after it has been replaced, so anything it refers to only
+    /// needs to live until then.
+    pub fn set_interrupt(&self, interrupt: Option<Interrupt>) {
+        self.state.lock().unwrap().interrupt = interrupt;
+    }
+
+    /// Stops the timer once the task has finished. Returns an error if the task timed out.
+    pub fn finish(self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.interrupt = None;
+
+        if state.status == TIMED_OUT {
+            self.processes.resume(&self.task);
+
+            let seconds = self.timeout.as_secs() as f64 + self.timeout.subsec_nanos() as f64 / 1e9;
+            return Err(format!("timed out after {}s", seconds));
+        }
+
+        state.status = FINISHED;
+        self.timer.cancel(self.callback);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use libc;
+    use std::process::{Command, Stdio};
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+    use timer::Timer;
+
+    /// Gets the process group of a child that has not been waited on yet.
+    fn process_group(id: u32) -> libc::pid_t {
+        unsafe { libc::getpgid(id as libc::pid_t) }
+    }
+
+    #[test]
+    fn children_lead_their_own_group() {
+        let processes = Processes::new();
+        let mut command = Command::new("sleep");
+        command.arg("1").stdout(Stdio::null());
+
+        let mut child = processes.spawn("build", &mut command).unwrap();
+        assert_eq!(process_group(child.id()), child.id() as libc::pid_t);
+        assert_eq!(processes.len(), 1);
+
+        processes.kill_task("build");
+        assert!(!processes.wait(&mut child).unwrap().success());
+        assert_eq!(processes.len(), 0);
+        assert!(processes.spawn("build", &mut command).is_err());
+    }
+
+    #[test]
+    fn foreground_children_stay_in_our_group() {
+        let processes = Processes::new();
+        processes.set_foreground("console", true);
+
+        let mut child = processes.spawn("console", &mut Command::new("true")).unwrap();
+        assert_eq!(process_group(child.id()), unsafe { libc::getpgid(0) });
+        processes.wait(&mut child).unwrap();
+    }
+
+    #[test]
+    fn deadlines_stop_tasks_that_run_too_long() {
+        let processes = Arc::new(Processes::new());
+        let timer = Arc::new(Timer::new());
+        let (sender, receiver) = mpsc::channel();
+
+        let deadline = Deadline::start("slow", Duration::from_millis(10), &processes, &timer);
+        deadline.set_interrupt(Some(Box::new(move || sender.send(()).unwrap())));
+
+        receiver.recv().unwrap();
+        assert!(processes.is_killed("slow"));
+        assert_eq!(deadline.finish(), Err("timed out after 0.01s".to_string()));
+        assert!(!processes.is_killed("slow"));
+    }
+
+    #[test]
+    fn finished_deadlines_are_cancelled() {
+        let processes = Arc::new(Processes::new());
+        let timer = Arc::new(Timer::new());
+
+        let deadline = Deadline::start("fast", Duration::from_millis(10), &processes, &timer);
+        assert_eq!(deadline.finish(), Ok(()));
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(!processes.is_killed("fast"));
+    }
+}
@@ -0,0 +1,267 @@
+use graph::Graph;
+use rule::Rule;
+use runtime::Environment;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use task::Kind;
+
+
+/// A question about the task graph that can be answered without running anything.
+pub enum Query {
+    /// Every task that a task depends on, directly or indirectly.
+    Deps(String),
+
+    /// Every task that depends on a task or file, directly or indirectly.
+    Rdeps(String),
+
+    /// A chain of dependencies leading from one task to another.
+    Path(String, String),
+
+    /// Every file needed by the Rotefile that is produced by the rule with the given pattern.
+    Outputs(String),
+}
+
+impl Query {
+    /// Parses a query from command-line arguments, such as `deps build`.
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Query, Box<Error>> {
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_ref()).collect();
+
+        match (args.first().cloned(), args.len()) {
+            (Some("deps"), 2) => Ok(Query::Deps(args[1].to_string())),
+            (Some("rdeps"), 2) => Ok(Query::Rdeps(args[1].to_string())),
+            (Some("path"), 3) => Ok(Query::Path(args[1].to_string(), args[2].to_string())),
+            (Some("outputs"), 2) => Ok(Query::Outputs(args[1].to_string())),
+            (None, _) => Err("no query given; expected deps, rdeps, path, or outputs".into()),
+            _ => {
+                Err(format!("invalid query '{}'; usage: --query deps <task> | rdeps <file> | \
+                             path <from> <to> | outputs <rule-pattern>",
+                            args.join(" "))
+                    .into())
+            }
+        }
+    }
+
+    /// Gets the tasks that must be resolved in the graph to answer the query, or `None` if every
+    /// named task must be resolved.
+    pub fn targets(&self) -> Option<Vec<&str>> {
+        match *self {
+            Query::Deps(ref task) => Some(vec![task.as_str()]),
+            Query::Path(ref from, _) => Some(vec![from.as_str()]),
+            Query::Rdeps(_) | Query::Outputs(_) => None,
+        }
+    }
+
+    /// Answers the query, returning a list of task or file names.
+    pub fn run(&self,
+               graph: &Graph,
+               environment: &Environment)
+               -> Result<Vec<String>, Box<Error>> {
+        match *self {
+            Query::Deps(ref task) => deps(graph, task),
+            Query::Rdeps(ref task) => rdeps(graph, task),
+            Query::Path(ref from, ref to) => path(graph, from, to),
+            Query::Outputs(ref pattern) => outputs(graph, environment, pattern),
+        }
+    }
+}
+
+/// Finds every task that a task depends on, sorted by name.
+fn deps(graph: &Graph, name: &str) -> Result<Vec<String>, Box<Error>> {
+    let task = try!(graph.get(name));
+    let mut found = HashSet::new();
+    let mut stack = try!(graph.dependencies(&*task));
+
+    while let Some(name) = stack.pop() {
+        if found.insert(name.clone()) {
+            stack.extend(try!(graph.dependencies(&*try!(graph.get(&name)))));
+        }
+    }
+
+    let mut found: Vec<String> = found.into_iter().collect();
+    found.sort();
+
+    Ok(found)
+}
+
+/// Finds every task that depends on a task or file, sorted by name.
+fn rdeps(graph: &Graph, name: &str) -> Result<Vec<String>, Box<Error>> {
+    let task = match graph.get(name) {
+        Ok(task) => task,
+        Err(_) => return Err(format!("'{}' is not used by any task", name).into()),
+    };
+
+    let dependents = try!(dependents(graph));
+    let mut found = HashSet::new();
+    let mut stack = vec![task.name().to_string()];
+
+    while let Some(name) = stack.pop() {
+        if let Some(names) = dependents.get(&name) {
+            for dependent in names {
+                if found.insert(dependent.clone()) {
+                    stack.push(dependent.clone());
+                }
+            }
+        }
+    }
+
+    let mut found: Vec<String> = found.into_iter().collect();
+    found.sort();
+
+    Ok(found)
+}
+
+/// Finds the shortest chain of dependencies from one task to another, including both ends.
+fn path(graph: &Graph, from: &str, to: &str) -> Result<Vec<String>, Box<Error>> {
+    let from = try!(graph.get(from)).name().to_string();
+    let to = match graph.get(to) {
+        Ok(task) => task.name().to_string(),
+        Err(_) => return Err(format!("'{}' does not depend on '{}'", from, to).into()),
+    };
+
+    // Search breadth-first, remembering how each task was reached.
+    let mut previous: HashMap<String, String> = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(from.clone());
+
+    while let Some(name) = queue.pop_front() {
+        if name == to {
+            let mut path = vec![name];
+            while let Some(name) = previous.get(path.last().unwrap()).cloned() {
+                path.push(name);
+            }
+            path.reverse();
+
+            return Ok(path);
+        }
+
+        for dependency in try!(graph.dependencies(&*try!(graph.get(&name)))) {
+            if dependency != from && !previous.contains_key(&dependency) {
+                previous.insert(dependency.clone(), name.clone());
+                queue.push_back(dependency);
+            }
+        }
+    }
+
+    Err(format!("'{}' does not depend on '{}'", from, to).into())
+}
+
+/// Finds the outputs of every file task in the graph created by the rule with the given pattern.
+fn outputs(graph: &Graph,
+           environment: &Environment,
+           pattern: &str)
+           -> Result<Vec<String>, Box<Error>> {
+    let rules: Vec<_> = environment.rules()
+        .into_iter()
+        .filter(|rule| rule.patterns.iter().any(|p| p.as_str() == pattern))
+        .collect();
+
+    if rules.is_empty() {
+        return Err(format!("no rule with pattern '{}'", pattern).into());
+    }
+
+    let mut found = Vec::new();
+
+    for task in graph.tasks() {
+        if task.kind() != Kind::File {
+            continue;
+        }
+
+        // The task belongs to the rule if the rule is the one chosen to build it.
+        if let Some((rule, _)) = environment.find_rule(task.name()) {
+            if rules.iter().any(|r| &**r as *const Rule == &*rule as *const Rule) {
+                found.extend(task.outputs().iter().cloned());
+            }
+        }
+    }
+
+    found.sort();
+    found.dedup();
+
+    Ok(found)
+}
+
+/// Maps the name of each task to the names of the tasks that depend on it directly.
+fn dependents(graph: &Graph) -> Result<HashMap<String, Vec<String>>, Box<Error>> {
+    let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+
+    for task in graph.tasks() {
+        for dependency in try!(graph.dependencies(&*task)) {
+            dependents.entry(dependency).or_insert_with(Vec::new).push(task.name().to_string());
+        }
+    }
+
+    Ok(dependents)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use graph::Graph;
+    use std::error::Error;
+    use std::rc::Rc;
+    use super::*;
+    use task::{NamedTask, Task};
+
+    fn task(name: &str, dependencies: &[&str]) -> Rc<Task> {
+        let dependencies: Vec<String> = dependencies.iter().map(|s| s.to_string()).collect();
+        Rc::new(NamedTask::new(name,
+                               None,
+                               dependencies,
+                               None::<fn() -> Result<(), Box<Error>>>))
+    }
+
+    /// A graph where `app` depends on `lib` and `gen`, and both depend on `config`.
+    fn graph() -> Graph {
+        let mut graph = Graph::new();
+        graph.insert(task("app", &["lib", "gen"]));
+        graph.insert(task("lib", &["config"]));
+        graph.insert(task("gen", &["config"]));
+        graph.insert(task("config", &[]));
+        graph.insert(task("docs", &[]));
+        graph
+    }
+
+    #[test]
+    fn parses_queries() {
+        match Query::parse(&["path", "app", "config"]).unwrap() {
+            Query::Path(ref from, ref to) => {
+                assert_eq!(from, "app");
+                assert_eq!(to, "config");
+            }
+            _ => panic!("expected a path query"),
+        }
+
+        assert_eq!(Query::parse(&["deps", "app"]).unwrap().targets(), Some(vec!["app"]));
+        assert_eq!(Query::parse(&["rdeps", "config"]).unwrap().targets(), None);
+        assert!(Query::parse(&["deps"]).is_err());
+        assert!(Query::parse(&["everything", "app"]).is_err());
+        assert!(Query::parse::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn deps_are_transitive() {
+        assert_eq!(deps(&graph(), "app").unwrap(), vec!["config", "gen", "lib"]);
+        assert!(deps(&graph(), "config").unwrap().is_empty());
+        assert!(deps(&graph(), "missing").is_err());
+    }
+
+    #[test]
+    fn rdeps_are_transitive() {
+        assert_eq!(rdeps(&graph(), "config").unwrap(), vec!["app", "gen", "lib"]);
+        assert!(rdeps(&graph(), "docs").unwrap().is_empty());
+        assert_eq!(rdeps(&graph(), "missing").err().unwrap().to_string(),
+                   "'missing' is not used by any task");
+    }
+
+    #[test]
+    fn path_is_shortest_chain() {
+        let chain = path(&graph(), "app", "config").unwrap();
+
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0], "app");
+        assert_eq!(chain[2], "config");
+        assert_eq!(path(&graph(), "lib", "lib").unwrap(), vec!["lib"]);
+        assert_eq!(path(&graph(), "config", "app").err().unwrap().to_string(),
+                   "'config' does not depend on 'app'");
+    }
+}
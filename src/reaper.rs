@@ -0,0 +1,623 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin};
+use std::sync::{Arc, Mutex};
+
+#[cfg(unix)]
+pub use self::unix::Reaper;
+#[cfg(not(unix))]
+pub use self::other::Reaper;
+
+
+/// Receives the output of a child process as it is read.
+pub trait Sink: Send {
+    /// Receives a chunk of output.
+    fn write(&mut self, data: &[u8]);
+
+    /// Called once the child has closed the stream.
+    fn close(&mut self) {}
+}
+
+/// Collects output in memory.
+impl Sink for Arc<Mutex<Vec<u8>>> {
+    fn write(&mut self, data: &[u8]) {
+        self.lock().unwrap().extend_from_slice(data);
+    }
+}
+
+/// Called with the exit code of a child once it has exited and closed its output streams. The
+/// code is `None` if the child was killed by a signal.
+pub type Done = Box<FnMut(io::Result<Option<i32>>) + Send>;
+
+/// A child process to wait for, along with what to do with its input and output.
+///
+/// The input is written and the output read while waiting, so that a child never blocks on a full
+/// pipe. Output streams without a sink are read and thrown away.
+pub struct Process {
+    child: Child,
+    input: Option<Input>,
+    streams: Vec<Stream>,
+}
+
+/// Data left to write to the standard input of a child.
+struct Input {
+    pipe: ChildStdin,
+    data: Vec<u8>,
+    written: usize,
+}
+
+/// An output stream of a child that is still open.
+struct Stream {
+    pipe: Box<Pipe>,
+    sink: Option<Box<Sink>>,
+    is_stderr: bool,
+}
+
+/// A pipe that output can be read from.
+#[cfg(unix)]
+trait Pipe: Read + Send + ::std::os::unix::io::AsRawFd {}
+#[cfg(unix)]
+impl<T: Read + Send + ::std::os::unix::io::AsRawFd> Pipe for T {}
+
+#[cfg(not(unix))]
+trait Pipe: Read + Send {}
+#[cfg(not(unix))]
+impl<T: Read + Send> Pipe for T {}
+
+impl Process {
+    /// Takes over the input and output pipes of a child.
+    pub fn new(mut child: Child) -> Process {
+        let mut streams = Vec::new();
+
+        if let Some(stdout) = child.stdout.take() {
+            streams.push(Stream {
+                pipe: Box::new(stdout),
+                sink: None,
+                is_stderr: false,
+            });
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            streams.push(Stream {
+                pipe: Box::new(stderr),
+                sink: None,
+                is_stderr: true,
+            });
+        }
+
+        Process {
+            child: child,
+            input: None,
+            streams: streams,
+        }
+    }
+
+    /// Gets the process ID of the child.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Writes data to the standard input of the child, which is closed afterwards. Does nothing if
+    /// the standard input of the child is not piped.
+    pub fn set_input(&mut self, data: Vec<u8>) {
+        if let Some(pipe) = self.child.stdin.take() {
+            self.input = Some(Input {
+                pipe: pipe,
+                data: data,
+                written: 0,
+            });
+        }
+    }
+
+    /// Sends the standard output of the child to a sink, if it is piped.
+    pub fn set_stdout(&mut self, sink: Box<Sink>) {
+        self.set_sink(false, sink);
+    }
+
+    /// Sends the standard error of the child to a sink, if it is piped.
+    pub fn set_stderr(&mut self, sink: Box<Sink>) {
+        self.set_sink(true, sink);
+    }
+
+    fn set_sink(&mut self, is_stderr: bool, sink: Box<Sink>) {
+        if let Some(stream) = self.streams.iter_mut().find(|stream| stream.is_stderr == is_stderr) {
+            stream.sink = Some(sink);
+        }
+    }
+
+    /// Checks if the input has been written and the output streams have been closed.
+    fn is_closed(&self) -> bool {
+        self.input.is_none() && self.streams.is_empty()
+    }
+
+    /// Closes the standard input of the child, if not closed already.
+    ///
+    /// A child that has no standard input waiting for it would otherwise wait forever.
+    fn close_input(&mut self) {
+        self.input = None;
+    }
+
+    /// Writes some of the input, closing the pipe once everything has been written.
+    fn write_input(&mut self) {
+        let finished = match self.input {
+            Some(ref mut input) => input.write(),
+            None => false,
+        };
+
+        if finished {
+            self.close_input();
+        }
+    }
+
+    /// Reads a chunk of output from a stream, removing the stream once the child has closed it.
+    fn read_stream(&mut self, index: usize) {
+        if self.streams[index].read() {
+            self.streams.remove(index);
+        }
+    }
+}
+
+impl Input {
+    /// Writes as much of the data as a pipe that is ready for writing takes without blocking.
+    /// Returns true once everything has been written, or the child has stopped reading, in which
+    /// case the rest is dropped.
+    fn write(&mut self) -> bool {
+        // A pipe that is ready for writing always has room for this much.
+        let end = cmp::min(self.written + 512, self.data.len());
+
+        match self.pipe.write(&self.data[self.written..end]) {
+            Ok(count) if count > 0 => {
+                self.written += count;
+                self.written == self.data.len()
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => false,
+            _ => true,
+        }
+    }
+}
+
+impl Stream {
+    /// Reads a chunk of output and passes it to the sink. Returns true once the child has closed
+    /// the stream.
+    fn read(&mut self) -> bool {
+        let mut chunk = [0; 4096];
+
+        let closed = match self.pipe.read(&mut chunk) {
+            Ok(0) => true,
+            Ok(count) => {
+                if let Some(ref mut sink) = self.sink {
+                    sink.write(&chunk[..count]);
+                }
+                false
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => false,
+            Err(_) => true,
+        };
+
+        if closed {
+            if let Some(ref mut sink) = self.sink {
+                sink.close();
+            }
+        }
+
+        closed
+    }
+}
+
+/// Gets the exit code from a raw wait status, or `None` if the child was killed by a signal.
+#[cfg(unix)]
+fn exit_code(status: i32) -> Option<i32> {
+    if status & 0x7f == 0 {
+        Some((status >> 8) & 0xff)
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use libc;
+    use processes::Processes;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicIsize, ATOMIC_ISIZE_INIT, Ordering};
+    use std::thread;
+    use super::{exit_code, Done, Process};
+
+
+    /// Write end of the pipe that wakes the reaper thread, or -1 once the reaper has stopped.
+    static WAKE: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+    /// How long the reaper sleeps at most while children are running, in milliseconds. Exits are
+    /// normally noticed right away by `SIGCHLD`; this only limits the delay should the signal be
+    /// delivered elsewhere.
+    const POLL_INTERVAL: libc::c_int = 100;
+
+    impl Process {
+        /// Adds the pipes that are still open to a list of descriptors to poll.
+        fn register(&self, fds: &mut Vec<libc::pollfd>) {
+            if let Some(ref input) = self.input {
+                fds.push(libc::pollfd {
+                    fd: input.pipe.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                });
+            }
+
+            for stream in &self.streams {
+                fds.push(libc::pollfd {
+                    fd: stream.pipe.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+        }
+
+        /// Handles the pipes that are ready, given the results of polling the descriptors added by
+        /// `register()`. Returns the number of descriptors that belonged to the process.
+        fn handle(&mut self, fds: &[libc::pollfd]) -> usize {
+            let mut count = 0;
+
+            if self.input.is_some() {
+                if fds[0].revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    self.close_input();
+                } else if fds[0].revents & libc::POLLOUT != 0 {
+                    self.write_input();
+                }
+                count += 1;
+            }
+
+            // Streams may be removed while reading, so go through them from the end.
+            let streams = self.streams.len();
+            for index in (0..streams).rev() {
+                if fds[count + index].revents != 0 {
+                    self.read_stream(index);
+                }
+            }
+
+            count + streams
+        }
+
+        /// Waits in place for the child to exit, passing its input and output along as it goes.
+        /// The child is removed from the registry of processes.
+        pub fn wait(mut self, processes: &Processes) -> io::Result<Option<i32>> {
+            while !self.is_closed() {
+                let mut fds = Vec::new();
+                self.register(&mut fds);
+
+                if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        // Waiting on the child is still possible, even if its pipes are not.
+                        self.close_input();
+                        self.streams.clear();
+                    }
+                    continue;
+                }
+
+                self.handle(&fds);
+            }
+
+            processes.wait(&mut self.child).map(|status| status.code())
+        }
+    }
+
+    /// Waits for child processes in the background, all on a single thread.
+    ///
+    /// The thread reads the output of every child and writes their input as the pipes become
+    /// ready. Children that exit are reaped when `SIGCHLD` arrives, and the callback of each child
+    /// is called once the child has exited and closed its output.
+    pub struct Reaper {
+        shared: Arc<Shared>,
+    }
+
+    /// State shared between the reaper and its thread.
+    struct Shared {
+        /// Children added since the thread last looked.
+        added: Mutex<Vec<(Process, Done)>>,
+
+        /// Set once the reaper has been dropped, to stop the thread.
+        stopped: AtomicBool,
+
+        processes: Arc<Processes>,
+
+        /// Read end of the pipe that wakes the thread.
+        wake_read: libc::c_int,
+
+        /// Write end of the pipe that wakes the thread.
+        wake_write: libc::c_int,
+    }
+
+    /// A child being waited on by the reaper thread.
+    struct Watched {
+        process: Process,
+        done: Done,
+
+        /// The exit code of the child once it has been reaped.
+        status: Option<io::Result<Option<i32>>>,
+    }
+
+    impl Reaper {
+        /// Starts the reaper thread. Children are removed from the given registry once reaped.
+        pub fn new(processes: Arc<Processes>) -> io::Result<Reaper> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for &fd in &fds {
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    let flags = libc::fcntl(fd, libc::F_GETFL);
+                    libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+                }
+            }
+
+            let shared = Arc::new(Shared {
+                added: Mutex::new(Vec::new()),
+                stopped: AtomicBool::new(false),
+                processes: processes,
+                wake_read: fds[0],
+                wake_write: fds[1],
+            });
+
+            WAKE.store(fds[1] as isize, Ordering::SeqCst);
+            install_handler();
+
+            let thread_shared = shared.clone();
+            thread::spawn(move || run(thread_shared));
+
+            Ok(Reaper {
+                shared: shared,
+            })
+        }
+
+        /// Waits for a child in the background, calling `done` on the reaper thread once the
+        /// child has exited.
+        pub fn watch(&self, process: Process, done: Done) {
+            self.shared.added.lock().unwrap().push((process, done));
+            wake(self.shared.wake_write);
+        }
+    }
+
+    impl Drop for Reaper {
+        fn drop(&mut self) {
+            self.shared.stopped.store(true, Ordering::SeqCst);
+            wake(self.shared.wake_write);
+        }
+    }
+
+    impl Drop for Shared {
+        fn drop(&mut self) {
+            // A handler still pointing at the pipe must not write to a closed descriptor.
+            let _ = WAKE.compare_and_swap(self.wake_write as isize, -1, Ordering::SeqCst);
+
+            unsafe {
+                libc::close(self.wake_read);
+                libc::close(self.wake_write);
+            }
+        }
+    }
+
+    /// Writes a byte to a wake pipe. If the pipe is full, the thread is already due to wake.
+    fn wake(fd: libc::c_int) {
+        let byte = 1u8;
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+
+    /// Installs a handler for `SIGCHLD` that wakes the reaper thread.
+    fn install_handler() {
+        extern "C" fn handle(_: libc::c_int) {
+            let fd = WAKE.load(Ordering::SeqCst);
+            if fd >= 0 {
+                wake(fd as libc::c_int);
+            }
+        }
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut());
+        }
+    }
+
+    /// Waits for children until the reaper is dropped.
+    fn run(shared: Arc<Shared>) {
+        let mut watched: Vec<Watched> = Vec::new();
+
+        while !shared.stopped.load(Ordering::SeqCst) {
+            for (process, done) in shared.added.lock().unwrap().drain(..) {
+                watched.push(Watched {
+                    process: process,
+                    done: done,
+                    status: None,
+                });
+            }
+
+            // Reap any children that have exited, and finish those that closed their output.
+            for child in &mut watched {
+                if child.status.is_none() {
+                    child.status = reap(child.process.id(), &shared.processes);
+                }
+            }
+
+            let mut index = 0;
+            while index < watched.len() {
+                if watched[index].status.is_some() && watched[index].process.is_closed() {
+                    let mut child = watched.remove(index);
+                    (child.done)(child.status.take().unwrap());
+                } else {
+                    index += 1;
+                }
+            }
+
+            let mut fds = vec![libc::pollfd {
+                                   fd: shared.wake_read,
+                                   events: libc::POLLIN,
+                                   revents: 0,
+                               }];
+            for child in &watched {
+                child.process.register(&mut fds);
+            }
+
+            let timeout = if watched.is_empty() { -1 } else { POLL_INTERVAL };
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+                continue;
+            }
+
+            if fds[0].revents != 0 {
+                let mut buffer = [0u8; 64];
+                while unsafe {
+                    libc::read(shared.wake_read,
+                               buffer.as_mut_ptr() as *mut libc::c_void,
+                               buffer.len())
+                } > 0 {}
+            }
+
+            let mut offset = 1;
+            for child in &mut watched {
+                offset += child.process.handle(&fds[offset..]);
+            }
+        }
+    }
+
+    /// Reaps a child if it has exited, removing it from the registry of processes.
+    fn reap(id: u32, processes: &Processes) -> Option<io::Result<Option<i32>>> {
+        let mut status = 0;
+
+        match unsafe { libc::waitpid(id as libc::pid_t, &mut status, libc::WNOHANG) } {
+            0 => None,
+            result => {
+                processes.remove(id);
+
+                if result < 0 {
+                    Some(Err(io::Error::last_os_error()))
+                } else {
+                    Some(Ok(exit_code(status)))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use processes::Processes;
+    use std::io;
+    use std::mem;
+    use std::sync::Arc;
+    use std::thread;
+    use super::{Done, Process};
+
+    impl Process {
+        /// Waits in place for the child to exit, passing its input and output along as it goes.
+        /// The child is removed from the registry of processes.
+        pub fn wait(mut self, processes: &Processes) -> io::Result<Option<i32>> {
+            let mut threads = Vec::new();
+
+            for mut stream in mem::replace(&mut self.streams, Vec::new()) {
+                threads.push(thread::spawn(move || while !stream.read() {}));
+            }
+
+            if let Some(mut input) = self.input.take() {
+                while !input.write() {}
+            }
+
+            for thread in threads {
+                let _ = thread.join();
+            }
+
+            processes.wait(&mut self.child).map(|status| status.code())
+        }
+    }
+
+    /// Waits for child processes in the background, using a thread for each child.
+    pub struct Reaper {
+        processes: Arc<Processes>,
+    }
+
+    impl Reaper {
+        pub fn new(processes: Arc<Processes>) -> io::Result<Reaper> {
+            Ok(Reaper {
+                processes: processes,
+            })
+        }
+
+        /// Waits for a child in the background, calling `done` once the child has exited.
+        pub fn watch(&self, process: Process, mut done: Done) {
+            let processes = self.processes.clone();
+            thread::spawn(move || done(process.wait(&processes)));
+        }
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use processes::Processes;
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use super::*;
+
+    fn spawn(processes: &Processes, script: &str) -> Process {
+        let mut command = Command::new("sh");
+        command.arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        Process::new(processes.spawn("test", &mut command).unwrap())
+    }
+
+    #[test]
+    fn input_is_written_while_output_is_read() {
+        // More than fits in a pipe, so that the child would block if either were left waiting.
+        let input = vec![b'x'; 256 * 1024];
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let processes = Processes::new();
+
+        let mut process = spawn(&processes, "cat");
+        process.set_input(input.clone());
+        process.set_stdout(Box::new(output.clone()));
+
+        assert_eq!(process.wait(&processes).unwrap(), Some(0));
+        assert_eq!(*output.lock().unwrap(), input);
+        assert_eq!(processes.len(), 0);
+    }
+
+    #[test]
+    fn reaper_waits_for_children_together() {
+        let processes = Arc::new(Processes::new());
+        let reaper = Reaper::new(processes.clone()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let output = Arc::new(Mutex::new(Vec::new()));
+
+        for &(name, script) in &[("slow", "sleep 0.3; exit 3"),
+                                 ("killed", "sleep 0.1; kill -9 $$"),
+                                 ("fast", "echo fast")] {
+            let mut process = spawn(&processes, script);
+            process.set_stdout(Box::new(output.clone()));
+
+            let sender = sender.clone();
+            reaper.watch(process,
+                         Box::new(move |status| sender.send((name, status.unwrap())).unwrap()));
+        }
+
+        let mut finished: Vec<_> = receiver.iter().take(3).collect();
+        finished.sort();
+
+        assert_eq!(finished, vec![("fast", Some(0)), ("killed", None), ("slow", Some(3))]);
+        assert_eq!(*output.lock().unwrap(), b"fast\n");
+        assert_eq!(processes.len(), 0);
+    }
+}
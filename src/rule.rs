@@ -1,118 +1,687 @@
+use database::{self, CommandLine, Database, Entry};
+use pattern::{Captures, Pattern};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::rc::Rc;
-use task;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use task::{self, PreparedCall, Status};
 
 
-type ActionFn = Fn(&str) -> Result<(), Box<Error>>;
+/// A rule action. The action is given the list of outputs to produce and the stems captured from
+/// the rule patterns.
+type ActionFn = Fn(&[String], &Captures) -> Result<(), Box<Error>>;
+
+/// Prepares a rule action to be called as a coroutine, given the same arguments as the action.
+type StartFn = Fn(&[String], &Captures) -> Result<PreparedCall, Box<Error>>;
+
+/// Takes the command lines executed by the rule action since it was started.
+type CommandsFn = Fn() -> Vec<CommandLine>;
+
+/// Expands the variables in a command argument, the same way as when the command is executed.
+type ExpandFn = Fn(&str) -> String;
+
+/// Current content digests of files, or `None` for files that cannot be read.
+type Digests = HashMap<String, Option<String>>;
 
 /// A rule task that matches against files. Rules are used to generate tasks from file name
 /// patterns.
+///
+/// A rule may produce more than one output file. Each output has its own pattern, and a single
+/// task is created that produces all of the outputs at once.
 pub struct Rule {
-    /// The file pattern to match.
-    pub pattern: String,
+    /// The file patterns of the outputs to match.
+    pub patterns: Vec<Pattern>,
 
     /// A list of tasks that must be ran before this task.
     dependencies: Vec<String>,
 
     /// Rule action.
     action: Option<Rc<ActionFn>>,
+
+    /// Prepares the action to be called as a coroutine.
+    start: Option<Rc<StartFn>>,
+
+    /// A fingerprint identifying the rule action, used to detect when the action changes.
+    fingerprint: Option<String>,
+
+    /// Takes the command lines executed by the action, so they can be recorded.
+    commands: Option<Rc<CommandsFn>>,
+
+    /// Expands recorded command lines again, to detect when they would change.
+    expand: Option<Rc<ExpandFn>>,
+
+    /// Indicates if content digests should be compared instead of modification times.
+    checksum: bool,
+
+    /// Priority used to choose between rules that match a file equally well.
+    priority: i64,
+
+    /// The pool that tasks created by this rule belong to.
+    pool: Option<String>,
+
+    /// How long tasks created by this rule are allowed to run.
+    timeout: Option<Duration>,
+
+    /// How many times tasks created by this rule are run again if they fail.
+    retries: u32,
+
+    /// How long to wait before running a failed task again.
+    retry_delay: Duration,
+
+    /// Build database that tasks created by this rule record their state in.
+    database: Option<Arc<Mutex<Database>>>,
 }
 
 impl Rule {
-    pub fn new<S, V, F>(pattern: S, dependencies: V, action: Option<F>) -> Rule
-        where S: Into<String>,
+    pub fn new<P, V, F>(patterns: P, dependencies: V, action: Option<F>) -> Rule
+        where P: Into<Vec<Pattern>>,
               V: Into<Vec<String>>,
-              F: Fn(&str) -> Result<(), Box<Error>> + 'static
+              F: Fn(&[String], &Captures) -> Result<(), Box<Error>> + 'static
     {
         Rule {
-            pattern: pattern.into(),
+            patterns: patterns.into(),
             dependencies: dependencies.into(),
             action: action.map(|a| Rc::new(a) as Rc<ActionFn>),
+            start: None,
+            fingerprint: None,
+            commands: None,
+            expand: None,
+            checksum: false,
+            priority: 0,
+            pool: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            database: None,
         }
     }
 
-    /// Checks if a file name matches the rule.
+    /// Gets the dependencies of the rule, before any stems are expanded.
+    pub fn inputs(&self) -> &[String] {
+        &self.dependencies
+    }
+
+    /// Replaces the dependencies of the rule.
+    pub fn set_inputs(&mut self, dependencies: Vec<String>) {
+        self.dependencies = dependencies;
+    }
+
+    /// Sets the priority of the rule.
+    ///
+    /// When more than one rule matches a file equally well, the rule with the highest priority is
+    /// chosen.
+    pub fn set_priority(&mut self, priority: i64) {
+        self.priority = priority;
+    }
+
+    /// Sets the pool that tasks created by this rule belong to.
+    pub fn set_pool<S: Into<String>>(&mut self, pool: S) {
+        self.pool = Some(pool.into());
+    }
+
+    /// Sets how long tasks created by this rule are allowed to run.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Sets how many times tasks created by this rule are run again if they fail, and how long to
+    /// wait before each new attempt.
+    pub fn set_retries(&mut self, retries: u32, delay: Duration) {
+        self.retries = retries;
+        self.retry_delay = delay;
+    }
+
+    /// Sets the build database that tasks created by this rule record their state in.
+    pub fn set_database(&mut self, database: Arc<Mutex<Database>>) {
+        self.database = Some(database);
+    }
+
+    /// Sets how to prepare the rule action to be called as a coroutine.
+    pub fn set_start<F>(&mut self, start: F)
+        where F: Fn(&[String], &Captures) -> Result<PreparedCall, Box<Error>> + 'static
+    {
+        self.start = Some(Rc::new(start));
+    }
+
+    /// Sets the fingerprint of the rule action.
+    ///
+    /// If the fingerprint differs from the one recorded in the build database when an output was
+    /// last built, the output is considered out of date.
+    pub fn set_fingerprint<S: Into<String>>(&mut self, fingerprint: S) {
+        self.fingerprint = Some(fingerprint.into());
+    }
+
+    /// Records the command lines the rule action executes in the build database.
+    ///
+    /// `commands` takes the command lines executed since the action was started, and `expand`
+    /// expands the variables in a command argument. If expanding a recorded command line again
+    /// gives different arguments than the ones it ran with, the output is considered out of date.
+    pub fn track_commands<C, E>(&mut self, commands: C, expand: E)
+        where C: Fn() -> Vec<CommandLine> + 'static,
+              E: Fn(&str) -> String + 'static
+    {
+        self.commands = Some(Rc::new(commands));
+        self.expand = Some(Rc::new(expand));
+    }
+
+    /// Enables checksum mode for tasks created by this rule.
+    ///
+    /// In checksum mode, the content digests of inputs and outputs are recorded in the build
+    /// database and compared instead of relying only on file modification times.
+    pub fn use_checksums(&mut self) {
+        self.checksum = true;
+    }
+
+    /// Checks if a file name matches any of the rule's output patterns.
     pub fn matches<S: AsRef<str>>(&self, name: S) -> bool {
-        if let Some(index) = self.pattern.find("%") {
-            let (prefix, suffix) = self.pattern.split_at(index);
-            let suffix = &suffix[1..];
+        self.patterns.iter().any(|pattern| pattern.captures(name.as_ref()).is_some())
+    }
 
-            name.as_ref().starts_with(prefix) && name.as_ref().ends_with(suffix)
-        } else {
-            &self.pattern == name.as_ref()
-        }
+    /// Determines how specifically the rule matches a file name.
+    ///
+    /// Returns `None` if the file name does not match any of the rule's output patterns.
+    pub fn specificity<S: AsRef<str>>(&self, name: S) -> Option<Specificity> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| {
+                pattern.captures(name.as_ref()).map(|captures| {
+                    // A regular expression without groups captures nothing, but still matches
+                    // the whole name loosely, so it counts as a stem as long as the name.
+                    let stem_length = if !pattern.is_literal() && captures.positional.is_empty() {
+                        name.as_ref().len()
+                    } else {
+                        captures.stem_length()
+                    };
+
+                    Specificity {
+                        exact: pattern.is_literal(),
+                        stem_length: stem_length,
+                        priority: self.priority,
+                    }
+                })
+            })
+            .max()
     }
 
     /// Creates a task for a given file based on the rule.
+    ///
+    /// The file may be any one of the rule's outputs; the task created is the same regardless of
+    /// which output is requested.
     pub fn create_task<S: Into<String>>(&self, name: S) -> Option<FileTask> {
         let name = name.into();
 
-        // First, check if the given filename matches.
-        if !self.matches(&name) {
-            return None;
-        }
-
-        // Clone the input files (dependencies).
-        let mut inputs = self.dependencies.clone();
-
-        // If the rule name is a pattern, determine the value of the replacement character "%".
-        if let Some(index) = self.pattern.find("%") {
-            let end = index + 1 + name.len() - self.pattern.len();
-            let replacement = &name[index..end];
+        // First, determine the values of the stems from the first pattern that matches the given
+        // filename.
+        let (matched, captures) = match self.patterns
+            .iter()
+            .enumerate()
+            .filter_map(|(i, pattern)| pattern.captures(&name).map(|captures| (i, captures)))
+            .next() {
+            Some(result) => result,
+            None => return None,
+        };
 
-            // Expand the inputs with the corresponding names that match the output name.
-            inputs = inputs.into_iter()
-                .map(|input| input.replace("%", replacement))
-                .collect();
-        }
+        // Expand the outputs and inputs with the stems that match the given name.
+        let outputs = self.patterns.iter()
+            .enumerate()
+            .map(|(i, output)| {
+                if i == matched {
+                    name.clone()
+                } else {
+                    captures.expand(output.as_str())
+                }
+            })
+            .collect();
+        let inputs = self.dependencies.iter()
+            .map(|input| captures.expand(input))
+            .collect();
 
         Some(FileTask {
             inputs: inputs,
-            output: name,
+            outputs: outputs,
+            captures: captures,
             action: self.action.clone(),
+            start: self.start.clone(),
+            fingerprint: self.fingerprint.clone(),
+            commands: self.commands.clone(),
+            expand: self.expand.clone(),
+            checksum: self.checksum,
+            pool: self.pool.clone(),
+            timeout: self.timeout,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            database: self.database.clone(),
         })
     }
 }
 
+/// Describes how specifically a rule matches a file name, so that the best rule for a file can be
+/// chosen when more than one rule matches.
+///
+/// A rule naming the file exactly is more specific than any pattern. Between patterns, the one
+/// with the shortest stem is more specific, and the rule priority breaks any remaining ties. A
+/// regular expression without groups counts as having a stem as long as the whole file name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Specificity {
+    /// Indicates if the file name matched a literal file name rather than a pattern.
+    exact: bool,
+
+    /// The total length of the matched stems.
+    stem_length: usize,
+
+    /// The priority of the rule.
+    priority: i64,
+}
+
+impl Ord for Specificity {
+    fn cmp(&self, other: &Specificity) -> Ordering {
+        // Shorter stems are more specific, so the stem lengths are compared in reverse.
+        (self.exact, other.stem_length, self.priority)
+            .cmp(&(other.exact, self.stem_length, other.priority))
+    }
+}
+
+impl PartialOrd for Specificity {
+    fn partial_cmp(&self, other: &Specificity) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, pattern) in self.patterns.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}", pattern));
+        }
+
+        Ok(())
+    }
+}
+
 pub struct FileTask {
     pub inputs: Vec<String>,
-    pub output: String,
+    pub outputs: Vec<String>,
+    captures: Captures,
     action: Option<Rc<ActionFn>>,
+    start: Option<Rc<StartFn>>,
+    fingerprint: Option<String>,
+    commands: Option<Rc<CommandsFn>>,
+    expand: Option<Rc<ExpandFn>>,
+    checksum: bool,
+    pool: Option<String>,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
+    database: Option<Arc<Mutex<Database>>>,
+}
+
+impl FileTask {
+    /// Checks if the task is dirty by comparing the file modification time of the input and output
+    /// files. If any of the input files are newer than the oldest output file, or if any output
+    /// file is missing, then the task is dirty.
+    fn status_by_mtime(&self) -> Status {
+        let mut oldest: Option<(&str, SystemTime)> = None;
+
+        for output in &self.outputs {
+            match fs::metadata(output).and_then(|m| m.modified()) {
+                Ok(time) => {
+                    if oldest.map(|(_, oldest)| time < oldest).unwrap_or(true) {
+                        oldest = Some((output.as_str(), time));
+                    }
+                }
+                Err(_) => return Status::Outdated(format!("output '{}' missing", output)),
+            }
+        }
+
+        let (output, time) = match oldest {
+            Some(oldest) => oldest,
+            None => return Status::Outdated("rule has no outputs".to_string()),
+        };
+
+        for input in &self.inputs {
+            if let Ok(input_time) = fs::metadata(input).and_then(|m| m.modified()) {
+                if input_time > time {
+                    return Status::Outdated(format!("input '{}' newer than output '{}' \
+                                                     (timestamps {} > {})",
+                                                    input,
+                                                    output,
+                                                    timestamp(input_time),
+                                                    timestamp(time)));
+                }
+            }
+        }
+
+        Status::Satisfied
+    }
+
+    /// Checks if an output is dirty by comparing the current content digests of the input and
+    /// output files against the digests recorded the last time the task was run.
+    fn status_by_checksum(&self, output: &str, entry: &Entry, digests: &Digests) -> Status {
+        // The output must still exist and not have been modified since it was built.
+        match digests.get(output) {
+            Some(&Some(ref digest)) => {
+                if entry.output.as_ref() != Some(digest) {
+                    return Status::Outdated(format!("output '{}' changed since it was built",
+                                                    output));
+                }
+            }
+            _ => return Status::Outdated(format!("output '{}' missing", output)),
+        }
+
+        // Every input must have the same digest as when the output was built. Inputs that are not
+        // files (such as named tasks) are ignored, unless they were files when it was built.
+        for input in &self.inputs {
+            match (digests.get(input.as_str()), entry.inputs.get(input)) {
+                (Some(&Some(ref digest)), recorded) => {
+                    if recorded != Some(digest) {
+                        return Status::Outdated(format!("input '{}' changed since output '{}' \
+                                                         was built (checksums differ)",
+                                                        input,
+                                                        output));
+                    }
+                }
+                (_, Some(_)) => {
+                    return Status::Outdated(format!("input '{}' missing since output '{}' was \
+                                                     built",
+                                                    input,
+                                                    output));
+                }
+                (_, None) => {}
+            }
+        }
+
+        Status::Satisfied
+    }
+
+    /// Computes the current content digests of every input and output file.
+    fn digests(&self) -> Digests {
+        self.inputs
+            .iter()
+            .chain(self.outputs.iter())
+            .map(|file| (file.clone(), database::digest_file(file)))
+            .collect()
+    }
+
+    /// Records the state of each output in the build database after the action has run.
+    fn record(&self, database: &Mutex<Database>) {
+        let mut entry = Entry::default();
+        entry.action = self.fingerprint.clone();
+        if let Some(ref commands) = self.commands {
+            entry.commands = commands();
+        }
+
+        // Content digests are only needed when checksum mode is enabled.
+        if self.checksum {
+            for input in &self.inputs {
+                if let Some(digest) = database::digest_file(input) {
+                    entry.inputs.insert(input.clone(), digest);
+                }
+            }
+        }
+
+        let entries: Vec<_> = self.outputs.iter()
+            .map(|output| {
+                let mut entry = entry.clone();
+
+                if self.checksum {
+                    entry.output = database::digest_file(output);
+                }
+
+                (output.clone(), entry)
+            })
+            .collect();
+
+        let mut database = database.lock().unwrap();
+        for (output, entry) in entries {
+            database.insert(output, entry);
+        }
+    }
+
+    /// Checks if a single output is satisfied according to its entry in the build database.
+    fn status_of_output(&self, output: &str, database: &Database, digests: &Digests) -> Status {
+        match database.get(output) {
+            Some(entry) => {
+                // Rebuild if the action, or a variable it uses, is different than when it
+                // built the output.
+                if self.fingerprint.is_some() && entry.action != self.fingerprint {
+                    return Status::Outdated(format!("rule action or the variables it uses \
+                                                     changed since output '{}' was built",
+                                                    output));
+                }
+
+                // Rebuild if a command line would now be expanded differently, such as when a
+                // variable given with `-D` or an environment variable has changed.
+                if let Some(ref expand) = self.expand {
+                    for command in &entry.commands {
+                        let args: Vec<String> = command.template
+                            .iter()
+                            .map(|arg| expand(arg))
+                            .collect();
+
+                        if args != command.args {
+                            return Status::Outdated(format!("command line '{}' changed to '{}' \
+                                                             since output '{}' was built",
+                                                            command.args.join(" "),
+                                                            args.join(" "),
+                                                            output));
+                        }
+                    }
+                }
+
+                if self.checksum {
+                    self.status_by_checksum(output, entry, digests)
+                } else {
+                    Status::Satisfied
+                }
+            }
+            // Without a recorded entry, there is nothing to compare checksums against. Outputs
+            // built before the database existed can still be checked by their timestamps.
+            None if self.checksum => {
+                Status::Outdated(format!("no checksums recorded for output '{}'", output))
+            }
+            None => Status::Satisfied,
+        }
+    }
 }
 
 impl task::Task for FileTask {
+    /// Gets the name of the task, which is the name of the first output.
     fn name<'a>(&'a self) -> &'a str {
-        &self.output
+        &self.outputs[0]
     }
 
-    /// Checks if the task is dirty by comparing the file modification time of the input and output
-    /// files. If any of the input files are newer than the output file, then the task is dirty.
-    fn satisfied(&self) -> bool {
-        fs::metadata(&self.output)
-            .and_then(|m| m.modified())
-            .map(|time| {
-                self.inputs
-                    .iter()
-                    .all(|input| {
-                        fs::metadata(input)
-                            .and_then(|m| m.modified())
-                            .map(|t| t <= time)
-                            .unwrap_or(true)
-                    })
-            })
-            .unwrap_or(false)
+    fn kind(&self) -> task::Kind {
+        task::Kind::File
+    }
+
+    fn outputs(&self) -> &[String] {
+        &self.outputs
+    }
+
+    /// Checks if the task is dirty. The task is dirty if the rule action has changed since the
+    /// outputs were last built, or if the inputs have changed. Changed inputs are detected using
+    /// either file modification times or recorded content digests, depending on whether checksum
+    /// mode is enabled for the rule.
+    fn status(&self) -> Status {
+        if let Some(ref database) = self.database {
+            // Files are hashed before locking the database, so that other threads are not held up
+            // while reading large files.
+            let digests = if self.checksum {
+                self.digests()
+            } else {
+                Digests::new()
+            };
+            let database = database.lock().unwrap();
+
+            for output in &self.outputs {
+                let status = self.status_of_output(output, &database, &digests);
+                if !status.is_satisfied() {
+                    return status;
+                }
+            }
+
+            // Checksums are a replacement for comparing timestamps.
+            if self.checksum {
+                return Status::Satisfied;
+            }
+        }
+
+        self.status_by_mtime()
     }
 
     fn dependencies(&self) -> &[String] {
         &self.inputs
     }
 
+    fn pool(&self) -> Option<&str> {
+        self.pool.as_ref().map(|pool| pool.as_str())
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
     fn run(&self) -> Result<(), Box<Error>> {
-        if let Some(ref action) = self.action {
-            action(&self.output)
-        } else {
-            Ok(())
+        let outcome = match self.action {
+            Some(ref action) => action(&self.outputs, &self.captures),
+            None => Ok(()),
+        };
+
+        self.complete(outcome)
+    }
+
+    fn start(&self) -> Result<Option<PreparedCall>, Box<Error>> {
+        match self.start {
+            Some(ref start) => start(&self.outputs, &self.captures).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Records the outputs in the build database once the action has succeeded.
+    fn complete(&self, outcome: Result<(), Box<Error>>) -> Result<(), Box<Error>> {
+        try!(outcome);
+
+        if let Some(ref database) = self.database {
+            self.record(database);
         }
+
+        Ok(())
+    }
+}
+
+/// Formats a file modification time as seconds since the Unix epoch.
+fn timestamp(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => format!("{}.{:09}", duration.as_secs(), duration.subsec_nanos()),
+        Err(_) => "before 1970".to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use database::{CommandLine, Database};
+    use pattern::{Captures, Pattern};
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use task::Task;
+
+    fn rule(pattern: Pattern, priority: i64) -> Rule {
+        let mut rule = Rule::new(vec![pattern],
+                                 Vec::new(),
+                                 None::<fn(&[String], &Captures) -> Result<(), Box<Error>>>);
+        rule.set_priority(priority);
+        rule
+    }
+
+    #[test]
+    fn exact_names_beat_patterns() {
+        let exact = rule(Pattern::new("main.o").unwrap(), 0);
+        let pattern = rule(Pattern::new("%.o").unwrap(), 10);
+
+        assert!(exact.specificity("main.o") > pattern.specificity("main.o"));
+    }
+
+    #[test]
+    fn shorter_stems_are_more_specific() {
+        let short = rule(Pattern::new("src/%.o").unwrap(), 0);
+        let long = rule(Pattern::new("%.o").unwrap(), 0);
+
+        assert!(short.specificity("src/main.o") > long.specificity("src/main.o"));
+        assert!(long.specificity("main.o").is_some());
+        assert!(short.specificity("main.o").is_none());
+    }
+
+    #[test]
+    fn priority_breaks_ties() {
+        let low = rule(Pattern::new("%.o").unwrap(), 0);
+        let high = rule(Pattern::new("%.o").unwrap(), 1);
+
+        assert!(high.specificity("main.o") > low.specificity("main.o"));
+    }
+
+    #[test]
+    fn regex_without_groups_does_not_beat_patterns() {
+        let regex = rule(Pattern::regex(r".*\.o").unwrap(), 0);
+        let pattern = rule(Pattern::new("src/%.o").unwrap(), 0);
+
+        assert!(pattern.specificity("src/main.o") > regex.specificity("src/main.o"));
+    }
+
+    #[test]
+    fn only_successful_actions_are_recorded() {
+        let database = Arc::new(Mutex::new(Database::new("unused.json")));
+        let mut rule = rule(Pattern::new("%.o").unwrap(), 0);
+        rule.set_database(database.clone());
+        let task = rule.create_task("main.o").unwrap();
+
+        assert!(task.complete(Err("failed".into())).is_err());
+        assert!(database.lock().unwrap().get("main.o").is_none());
+
+        task.complete(Ok(())).unwrap();
+        assert!(database.lock().unwrap().get("main.o").is_some());
+    }
+
+    #[test]
+    fn changed_command_lines_are_outdated() {
+        let flags = Rc::new(RefCell::new("-O2".to_string()));
+        let database = Arc::new(Mutex::new(Database::new("unused.json")));
+        let mut rule = rule(Pattern::new("%.o").unwrap(), 0);
+        rule.set_database(database.clone());
+
+        let current = flags.clone();
+        rule.track_commands(|| {
+                                vec![CommandLine {
+                                         template: vec!["cc".to_string(), "$CFLAGS".to_string()],
+                                         args: vec!["cc".to_string(), "-O2".to_string()],
+                                     }]
+                            },
+                            move |arg| arg.replace("$CFLAGS", &current.borrow()));
+        let task = rule.create_task("main.o").unwrap();
+        task.complete(Ok(())).unwrap();
+
+        let database = database.lock().unwrap();
+        assert_eq!(database.get("main.o").unwrap().commands.len(), 1);
+        assert!(task.status_of_output("main.o", &database, &Digests::new()).is_satisfied());
+
+        *flags.borrow_mut() = "-O0".to_string();
+        assert!(!task.status_of_output("main.o", &database, &Digests::new()).is_satisfied());
     }
 }
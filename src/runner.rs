@@ -1,16 +1,42 @@
-use graph::Graph;
+use database::Database;
+use executor::{Executor, Finished};
+use glob;
+use graph::{self, Graph};
+use jobserver::{Jobserver, Tokens};
+use json::{self, JsonValue};
 use modules;
 use num_cpus;
-use runtime::{Environment, Runtime};
+use output;
+use processes::{self, Deadline, Processes};
+use query::Query;
+use runtime::{self, Environment, Notify, Reply, Runtime};
+use scheduler::Scheduler;
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use task::Task;
+use std::time::{Duration, Instant};
+use task::{Arguments, Kind, SourceFile, Task, CONSOLE_POOL};
 use term;
+use timer::Timer;
+use trace::Trace;
+use watcher::{Snapshot, Watcher};
+
+
+/// How long child processes are given to exit after being interrupted before they are killed.
+const GRACE_PERIOD_SECS: u64 = 5;
+
+/// How often to check if the load average has dropped while tasks are being held back.
+const LOAD_CHECK_INTERVAL_MS: u64 = 1000;
+
+/// How long to wait for a jobserver token before checking if the run has ended, in milliseconds.
+const TOKEN_WAIT_MS: u64 = 100;
 
 
 #[derive(Clone)]
@@ -33,15 +59,52 @@ pub struct EnvironmentSpec {
     /// Indicates if up-to-date tasks should be run anyway.
     always_run: bool,
 
-    /// Indicates task errors should be ignored.
+    /// Indicates if unrelated tasks should keep running after a task fails.
     keep_going: bool,
+
+    /// Indicates if rules should compare checksums instead of modification times.
+    checksums: bool,
+
+    /// How long tasks without a timeout of their own are allowed to run.
+    timeout: Option<Duration>,
+
+    /// How the output of tasks is displayed.
+    output: output::Mode,
+
+    /// The build database shared between all threads.
+    database: Arc<Mutex<Database>>,
+
+    /// Registry of child processes started by tasks in all threads.
+    processes: Arc<Processes>,
+
+    /// Timer that stops tasks once their timeout passes, shared between all threads.
+    timer: Arc<Timer>,
+
+    /// Timeline of the build, if it is being recorded.
+    trace: Option<Trace>,
+
+    /// Arguments given to tasks on the command line, keyed by task name.
+    arguments: HashMap<String, Arguments>,
+
+    /// Snapshot of the tasks and rules defined by the script, taken after it was evaluated on the
+    /// main thread. Worker threads define their tasks from the snapshot instead of evaluating the
+    /// script again.
+    snapshot: Option<Arc<String>>,
+
+    /// Indicates if a snapshot should be taken when the script is evaluated.
+    snapshots: bool,
 }
 
 impl EnvironmentSpec {
     /// Creates an environment from the environment specification.
     pub fn create(&self) -> Result<Runtime, Box<Error>> {
         // Prepare a new environment.
-        let environment = try!(Environment::new(self.path.clone()));
+        let environment = try!(Environment::new(self.path.clone(),
+                                               self.database.clone(),
+                                               self.processes.clone()));
+        environment.set_checksums(self.checksums);
+        environment.output().set_mode(self.output);
+        environment.set_arguments(self.arguments.clone());
         let runtime = Runtime::new(environment);
 
         // Open standard library functions.
@@ -69,13 +132,126 @@ impl EnvironmentSpec {
             runtime.state().set_global(&name);
         }
 
-        // Load the script.
-        try!(runtime.load());
+        // Define the tasks and rules from the snapshot if there is one, so that the script and any
+        // side effects it has are only evaluated once.
+        match self.snapshot {
+            Some(ref snapshot) => try!(modules::stdlib::restore_snapshot(&runtime, snapshot)),
+            None => {
+                if self.snapshots {
+                    modules::stdlib::start_snapshot(&runtime);
+                }
+
+                let result = runtime.load();
+
+                // Print anything the script printed while it was loading, since no task will.
+                runtime.environment().output().flush("");
+                try!(result);
+            }
+        }
 
         Ok(runtime)
     }
 }
 
+/// Messages sent from worker threads to the master thread.
+enum Message {
+    /// A thread is ready to run a task.
+    Idle(usize),
+
+    /// A thread finished running a task.
+    Finished {
+        thread: usize,
+        task: String,
+        duration: Duration,
+        attempts: u32,
+        result: Result<(), String>,
+    },
+
+    /// A thread failed to start and will not run any tasks.
+    Stopped(usize),
+
+    /// A token was acquired from the jobserver.
+    Token(u8),
+
+    /// Sent periodically to check if the load average has changed.
+    Tick,
+
+    /// The program was interrupted by a signal.
+    Interrupted,
+
+    /// An operation that a task running as a coroutine waits for has finished.
+    Completed(usize, Reply),
+}
+
+impl From<Finished> for Message {
+    fn from(finished: Finished) -> Message {
+        Message::Finished {
+            thread: finished.slot,
+            task: finished.task,
+            duration: finished.duration,
+            attempts: finished.attempts,
+            result: finished.result,
+        }
+    }
+}
+
+/// The outcome of each task in a run.
+#[derive(Default)]
+struct Summary {
+    /// Tasks that completed successfully.
+    succeeded: Vec<String>,
+
+    /// Tasks that failed.
+    failed: Vec<String>,
+
+    /// Tasks that were not run because a task they depend on failed.
+    skipped: Vec<String>,
+
+    /// Tasks that were stopped because the program was interrupted.
+    cancelled: Vec<String>,
+
+    /// Tasks that succeeded only after being retried, along with the number of attempts.
+    retried: Vec<(String, u32)>,
+}
+
+impl Summary {
+    /// Prints the summary at the end of a run.
+    ///
+    /// `not_started` is the number of tasks that were never started because the run was stopped
+    /// early.
+    fn print(&self, not_started: usize) {
+        info!("{} task(s) succeeded, {} failed, {} cancelled, {} skipped",
+              self.succeeded.len(),
+              self.failed.len(),
+              self.cancelled.len(),
+              self.skipped.len() + not_started);
+
+        for task in &self.succeeded {
+            info!("  succeeded: {}", task);
+        }
+
+        for task in &self.failed {
+            info!("  failed:    {}", task);
+        }
+
+        for task in &self.cancelled {
+            info!("  cancelled: {}", task);
+        }
+
+        for task in &self.skipped {
+            info!("  skipped:   {}", task);
+        }
+
+        for &(ref task, attempts) in &self.retried {
+            info!("  retried:   {} (passed on attempt {})", task, attempts);
+        }
+
+        if not_started > 0 {
+            info!("  {} other task(s) were not started", not_started);
+        }
+    }
+}
+
 /// A task runner object that holds the state for defined tasks, dependencies, and the scripting
 /// runtime.
 pub struct Runner {
@@ -85,11 +261,26 @@ pub struct Runner {
     /// The number of threads to use.
     jobs: usize,
 
+    /// No new tasks are started while the load average is above this limit.
+    max_load: Option<f64>,
+
     /// Environment specification.
     spec: EnvironmentSpec,
 
     /// Runtime local owned by the master thread.
     runtime: Option<Runtime>,
+
+    /// Jobserver shared with child processes, created on the first run.
+    jobserver: Option<Arc<Jobserver>>,
+
+    /// File to write the timeline of the build to.
+    trace_path: Option<PathBuf>,
+
+    /// Indicates if the reason each task has to run should be printed.
+    explain: bool,
+
+    /// Indicates if tasks should run as coroutines in the main runtime instead of in threads.
+    coroutines: bool,
 }
 
 impl Runner {
@@ -106,9 +297,18 @@ impl Runner {
             }
         };
 
+        // Open the build database. A broken database is not fatal; it only means that everything
+        // will be rebuilt.
+        let database_path = directory.join(".rote").join("db.json");
+        let database = Database::open(&database_path).unwrap_or_else(|e| {
+            warn!("failed to read build database: {}", e);
+            Database::new(&database_path)
+        });
+
         Ok(Runner {
             graph: Graph::new(),
             jobs: jobs as usize,
+            max_load: None,
             spec: EnvironmentSpec {
                 path: path.into(),
                 directory: directory,
@@ -117,8 +317,22 @@ impl Runner {
                 dry_run: false,
                 always_run: false,
                 keep_going: false,
+                checksums: false,
+                timeout: None,
+                output: output::Mode::Passthrough,
+                database: Arc::new(Mutex::new(database)),
+                processes: Arc::new(Processes::new()),
+                timer: Arc::new(Timer::new()),
+                trace: None,
+                arguments: HashMap::new(),
+                snapshot: None,
+                snapshots: true,
             },
             runtime: None,
+            jobserver: None,
+            trace_path: None,
+            explain: false,
+            coroutines: false,
         })
     }
 
@@ -143,16 +357,83 @@ impl Runner {
         self.spec.always_run = true;
     }
 
-    /// Run all tasks even if they throw errors.
+    /// Keep running tasks after a task fails.
+    ///
+    /// Only tasks that depend on a failed task, directly or indirectly, are skipped.
     pub fn keep_going(&mut self) {
         self.spec.keep_going = true;
     }
 
+    /// Print the reason why each task has to run.
+    pub fn explain(&mut self) {
+        self.explain = true;
+    }
+
+    /// Compare file checksums instead of modification times for all rules.
+    pub fn checksums(&mut self) {
+        self.spec.checksums = true;
+    }
+
+    /// Sets how long tasks are allowed to run, unless they set a timeout of their own.
+    pub fn timeout(&mut self, timeout: Duration) {
+        self.spec.timeout = Some(timeout);
+    }
+
+    /// Sets how the output of tasks is displayed.
+    pub fn output_mode(&mut self, mode: output::Mode) {
+        self.spec.output = mode;
+    }
+
+    /// Records a timeline of the build and writes it to a file in the Chrome Trace Event format.
+    pub fn trace<P: Into<PathBuf>>(&mut self, path: P) {
+        self.trace_path = Some(path.into());
+        self.spec.trace = Some(Trace::new());
+    }
+
+    /// Sets the arguments given to a task on the command line.
+    pub fn set_arguments<S: Into<String>>(&mut self, task: S, arguments: Arguments) {
+        self.spec.arguments.insert(task.into(), arguments);
+
+        // The main runtime has been created already, and runs tasks as coroutines.
+        if let Some(ref runtime) = self.runtime {
+            runtime.environment().set_arguments(self.spec.arguments.clone());
+        }
+    }
+
     /// Sets the number of threads to use to run tasks.
     pub fn jobs(&mut self, jobs: usize) {
         self.jobs = jobs;
     }
 
+    /// Runs tasks as coroutines in the main runtime, instead of in a runtime of their own on a
+    /// separate thread.
+    ///
+    /// A task gives way to other tasks whenever it waits for a command, so the number of jobs
+    /// limits how many tasks can wait at once. Every task shares the same globals.
+    pub fn coroutines(&mut self) {
+        self.coroutines = true;
+
+        // Tasks share the main runtime, so there is nothing to copy.
+        self.spec.snapshots = false;
+    }
+
+    /// Evaluates the script again in every worker thread, instead of copying the tasks and rules
+    /// it defines from the main thread.
+    ///
+    /// Scripts that store values that cannot be copied, such as userdata, need this to run tasks
+    /// on more than one thread.
+    pub fn no_snapshot(&mut self) {
+        self.spec.snapshots = false;
+    }
+
+    /// Sets the maximum system load average.
+    ///
+    /// While the load average is above the limit, no new tasks are started unless no tasks are
+    /// running at all.
+    pub fn max_load(&mut self, load: f64) {
+        self.max_load = Some(load);
+    }
+
     /// Adds a path to Lua's require path for modules.
     pub fn include_path<P: Into<PathBuf>>(&mut self, path: P) {
         self.spec.include_paths.push(path.into());
@@ -166,7 +447,21 @@ impl Runner {
     /// Load the script.
     pub fn load(&mut self) -> Result<(), Box<Error>> {
         if self.runtime.is_none() {
-            self.runtime = Some(try!(self.spec.create()));
+            let runtime = try!(self.spec.create());
+
+            if self.spec.snapshots {
+                match modules::stdlib::save_snapshot(&runtime) {
+                    Ok(snapshot) => self.spec.snapshot = Some(Arc::new(snapshot)),
+                    Err(e) => {
+                        return Err(format!("{}; pass --no-snapshot to evaluate the Rotefile in \
+                                            every worker thread instead",
+                                           e)
+                            .into());
+                    }
+                }
+            }
+
+            self.runtime = Some(runtime);
         }
 
         Ok(())
@@ -190,6 +485,19 @@ impl Runner {
             }
 
             writeln!(out, "").unwrap();
+
+            for param in &task.params {
+                write!(out, "    {:30}", param.usage()).unwrap();
+
+                if let Some(ref description) = param.description {
+                    write!(out, "{}", description).unwrap();
+                }
+                if let Some(ref default) = param.default {
+                    write!(out, " (default: {})", default).unwrap();
+                }
+
+                writeln!(out, "").unwrap();
+            }
         }
 
         if let Some(ref default) = self.runtime().environment().default_task() {
@@ -198,6 +506,67 @@ impl Runner {
         }
     }
 
+    /// Prints the graph of the specified tasks and everything they depend on, or of the default
+    /// task if none are given, instead of running them.
+    pub fn print_graph<S: AsRef<str>>(&mut self,
+                                      tasks: &[S],
+                                      format: graph::Format)
+                                      -> Result<(), Box<Error>> {
+        if tasks.is_empty() {
+            match self.runtime().environment().default_task() {
+                Some(name) => try!(self.resolve_task(name, None)),
+                None => return Err("no default task defined".into()),
+            }
+        }
+
+        for task in tasks {
+            try!(self.resolve_task(task, None));
+        }
+
+        match format {
+            graph::Format::Dot => print!("{}", try!(self.graph.to_dot())),
+            graph::Format::Json => println!("{}", json::stringify(try!(self.graph.to_json()))),
+        }
+
+        Ok(())
+    }
+
+    /// Answers a query about the task graph without running anything, printing the resulting
+    /// names one per line, or as a JSON array if `json` is set.
+    pub fn query<S: AsRef<str>>(&mut self, args: &[S], json: bool) -> Result<(), Box<Error>> {
+        let query = try!(Query::parse(args));
+
+        match query.targets() {
+            Some(targets) => {
+                for target in targets {
+                    try!(self.resolve_task(target, None));
+                }
+            }
+            None => self.resolve_all(),
+        }
+
+        let names = try!(query.run(&self.graph, self.runtime().environment()));
+
+        if json {
+            let mut array = JsonValue::new_array();
+            for name in names {
+                try!(array.push(name));
+            }
+            println!("{}", json::stringify(array));
+        } else {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the name of the default task, if one is defined.
+    pub fn default_task(&self) -> Option<String> {
+        self.runtime().environment().default_task()
+    }
+
     /// Run the default task.
     pub fn run_default(&mut self) -> Result<(), Box<Error>> {
         if let Some(ref name) = self.runtime().environment().default_task() {
@@ -213,28 +582,165 @@ impl Runner {
     /// Tasks are run in parallel when possible during execution. The maximum number of parallel
     /// jobs can be set with the `jobs()` method.
     pub fn run<S: AsRef<str>>(&mut self, tasks: &[S]) -> Result<(), Box<Error>> {
+        self.execute(tasks, None)
+    }
+
+    /// Runs the specified list of tasks, or the default task if none are given, and then keeps
+    /// running them again whenever the files they depend on change, until interrupted.
+    ///
+    /// Only the tasks affected by the changed files are run again.
+    pub fn watch<S: AsRef<str>>(&mut self, tasks: &[S]) -> Result<(), Box<Error>> {
+        let tasks: Vec<String> = if tasks.is_empty() {
+            match self.runtime().environment().default_task() {
+                Some(name) => vec![name],
+                None => return Err("no default task defined".into()),
+            }
+        } else {
+            tasks.iter().map(|task| task.as_ref().to_string()).collect()
+        };
+
+        // Handle interrupts from the start, so that they stop the watch loop gracefully.
+        processes::install_handlers();
+        let mut changed = None;
+
+        loop {
+            // Resolve the tasks again each time, since files may have been added or removed.
+            self.graph = Graph::new();
+
+            // Note the state of the watched files before running anything, so that files edited
+            // while the tasks run are noticed afterwards. Errors are reported by `execute()`.
+            let resolved = tasks.iter().all(|task| self.resolve_task(task, None).is_ok());
+            let before = if resolved {
+                self.watcher().snapshot()
+            } else {
+                Snapshot::new()
+            };
+
+            if let Err(e) = self.execute(&tasks, changed.as_ref()) {
+                error!("{}", e);
+            }
+
+            if processes::interrupted() {
+                return Ok(());
+            }
+
+            let watcher = self.watcher();
+            info!("watching {} file(s) for changes", watcher.len());
+
+            changed = match watcher.wait(&before) {
+                Some(changed) => {
+                    for path in &changed {
+                        debug!("'{}' changed", path);
+                    }
+
+                    if changed.contains(self.path().to_string_lossy().as_ref()) {
+                        warn!("the Rotefile changed; restart rote to pick up the changes");
+                    }
+
+                    Some(changed)
+                }
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Runs the specified list of tasks. If a set of changed files is given, only the tasks
+    /// affected by the changes are run.
+    fn execute<S: AsRef<str>>(&mut self,
+                              tasks: &[S],
+                              changed: Option<&HashSet<String>>)
+                              -> Result<(), Box<Error>> {
         // Resolve all tasks given.
         for task in tasks {
-            try!(self.resolve_task(task));
+            try!(self.resolve_task(task, None));
         }
 
+        try!(self.check_arguments());
+
         // Determine the schedule of tasks to execute.
-        let mut queue = try!(self.graph.solve(!self.spec.always_run));
-        let task_count = queue.len();
-        let thread_count = cmp::min(self.jobs, task_count);
+        let solve_start = Instant::now();
+        let mut queue = try!(self.graph.solve(!self.spec.always_run, self.explain));
+
+        if let Some(ref trace) = self.spec.trace {
+            trace.span("resolve", "runner", solve_start, Instant::now(), JsonValue::Null);
+
+            // Every task that was resolved but not scheduled was found to be up to date.
+            if !self.spec.always_run {
+                let scheduled: HashSet<&str> = queue.iter().map(|task| task.name()).collect();
+
+                for task in self.graph.tasks() {
+                    if !scheduled.contains(task.name()) {
+                        trace.instant(task.name(), "up-to-date", JsonValue::Null);
+                    }
+                }
+            }
+        }
+
+        if let Some(changed) = changed {
+            queue = self.affected(queue, changed);
+        }
 
-        debug!("running {} task(s) across {} thread(s)",
-               task_count,
-               thread_count);
+        // Prioritize tasks using how long they took the last time they were run. Tasks that have
+        // never been run before are assumed to be quick.
+        let pools = self.runtime().environment().pools();
+        let mut scheduler = {
+            let database = self.spec.database.lock().unwrap();
+
+            try!(Scheduler::new(&self.graph, queue, pools, |task| {
+                database.duration(task.name())
+                    .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000)
+                    .unwrap_or(1)
+            }))
+        };
 
-        // Spawn one thread for each job.
+        let task_count = scheduler.len();
+
+        // When run by make, its jobserver decides how many jobs can run.
+        let jobserver = self.jobserver();
+        let jobs = match jobserver {
+            Some(ref jobserver) if jobserver.is_client() => num_cpus::get(),
+            _ => self.jobs,
+        };
+
+        let thread_count = cmp::min(jobs, task_count);
+
+        // Spawn one thread for each job, unless tasks run as coroutines on this thread.
         let mut threads = Vec::new();
-        let mut free_threads: HashSet<usize> = HashSet::new();
         let mut channels = Vec::new();
-        let (sender, receiver) = mpsc::channel::<Result<usize, usize>>();
+        let (sender, receiver) = mpsc::channel::<Message>();
+        let mut coroutines = None;
+
+        if self.coroutines {
+            debug!("running {} task(s) as coroutines, up to {} at once",
+                   task_count,
+                   thread_count);
+
+            let notify = Mutex::new(sender.clone());
+            let notify: Notify = Arc::new(move |operation: usize, reply: Reply| {
+                let _ = notify.lock().unwrap().send(Message::Completed(operation, reply));
+            });
+            let executor = try!(Executor::new(self.runtime(),
+                                              notify,
+                                              thread_count,
+                                              self.spec.timeout,
+                                              self.spec.timer.clone(),
+                                              self.spec.trace.clone()));
+
+            // Each slot for a coroutine stands in for a thread.
+            for slot in 0..thread_count {
+                let _ = sender.send(Message::Idle(slot));
+            }
+
+            coroutines = Some((executor, sender.clone()));
+        } else {
+            debug!("running {} task(s) across {} thread(s)",
+                   task_count,
+                   thread_count);
+        }
 
         // Spawn `jobs` number of threads (but no more than the task count!).
-        for thread_id in 0..thread_count {
+        let worker_count = if self.coroutines { 0 } else { thread_count };
+        for thread_id in 0..worker_count {
             let spec = self.spec.clone();
             let thread_sender = sender.clone();
 
@@ -243,12 +749,20 @@ impl Runner {
 
             threads.push(thread::spawn(move || {
                 // Prepare a new runtime.
-                let runtime = spec.create().unwrap_or_else(|e| {
-                    error!("{}", e);
-                    panic!();
-                });
+                let runtime = match spec.create() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        error!("{}", e);
+                        let _ = thread_sender.send(Message::Stopped(thread_id));
+                        return;
+                    }
+                };
 
-                if thread_sender.send(Ok(thread_id)).is_err() {
+                // Worker threads come after the main thread in the timeline.
+                let trace = spec.trace.as_ref().map(|trace| trace.for_thread(thread_id + 1));
+                runtime.environment().set_trace(trace.clone());
+
+                if thread_sender.send(Message::Idle(thread_id)).is_err() {
                     trace!("thread {} failed to send channel", thread_id);
                 }
 
@@ -260,38 +774,56 @@ impl Runner {
                     let task = {
                         // Lookup the task to run.
                         if let Some(task) = runtime.environment().get_task(&name) {
-                            task as Rc<Task>
+                            Some(task as Rc<Task>)
+                        }
+                        // Find the rule that best matches the task name.
+                        else if let Some((rule, _)) = runtime.environment().find_rule(&name) {
+                            rule.create_task(name.clone()).map(|task| Rc::new(task) as Rc<Task>)
                         }
-                        // Find a rule that matches the task name.
-                        else if let Some(rule) = runtime.environment()
-                            .rules()
-                            .iter()
-                            .find(|rule| rule.matches(&name)) {
-                            Rc::new(rule.create_task(name).unwrap()) as Rc<Task>
+                        // The task is a source file.
+                        else if Path::new(&name).exists() {
+                            Some(Rc::new(SourceFile::new(name.clone())) as Rc<Task>)
                         }
                         // No matching task.
                         else {
-                            panic!("no matching task or rule for '{}'", name);
+                            None
                         }
                     };
 
+                    let start = Instant::now();
+
                     // Check for dry run.
-                    if !spec.dry_run {
-                        if let Err(e) = task.run() {
-                            // If we ought to keep going, just issue a warning.
-                            if spec.keep_going {
-                                warn!("ignoring error: {}", e);
-                            } else {
-                                error!("{}", e);
-                                thread_sender.send(Err(thread_id)).unwrap();
-                                return;
-                            }
+                    let (result, attempts) = match task {
+                        Some(ref task) if spec.dry_run => {
+                            info!("would run task '{}'", task.name());
+                            (Ok(()), 1)
+                        }
+                        Some(ref task) => run_with_retries(&**task, &runtime, &spec),
+                        None => (Err(format!("no matching task or rule for '{}'", name)), 1),
+                    };
+
+                    runtime.environment().output().flush(&name);
+
+                    if let Some(ref trace) = trace {
+                        let mut args = JsonValue::new_object();
+                        args["attempts"] = (attempts as f64).into();
+                        match result {
+                            Ok(()) => args["result"] = "ok".into(),
+                            Err(ref e) => args["result"] = e.as_str().into(),
                         }
-                    } else {
-                        info!("would run task '{}'", task.name());
+
+                        trace.span(name.as_str(), "task", start, Instant::now(), args);
                     }
 
-                    if thread_sender.send(Ok(thread_id)).is_err() {
+                    let message = Message::Finished {
+                        thread: thread_id,
+                        task: name,
+                        duration: start.elapsed(),
+                        attempts: attempts,
+                        result: result,
+                    };
+
+                    if thread_sender.send(message).is_err() {
                         trace!("thread {} failed to send channel", thread_id);
                         break;
                     }
@@ -299,125 +831,657 @@ impl Runner {
             }))
         }
 
-        drop(sender);
-
-        // Keep track of tasks completed and tasks in progress.
-        let mut completed_tasks: HashSet<String> = HashSet::new();
-        let mut current_tasks: HashMap<usize, String> = HashMap::new();
-        let all_tasks: HashSet<String> = queue.iter().map(|s| s.name().to_string()).collect();
+        // Watch for interrupt signals while tasks are running.
+        processes::install_handlers();
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let sender = sender.clone();
+            let processes = self.spec.processes.clone();
+            let done = done.clone();
 
-        while !queue.is_empty() || !current_tasks.is_empty() {
-            // Wait for a thread to request a task.
-            let result = receiver.recv().unwrap();
+            thread::spawn(move || watch_signals(sender, processes, done))
+        };
 
-            // If the thread sent an error, we should stop everything if keep_going isn't enabled.
-            if let Err(thread_id) = result {
-                debug!("thread {} errored, waiting for remaining tasks...",
-                       thread_id);
-                return Err("not all tasks completed successfully".into());
+        // Tokens are read from the jobserver in a separate thread, since reading blocks until a
+        // token is available.
+        let (token_requests, acquirer) = match jobserver {
+            Some(ref jobserver) => {
+                let (requests, thread_requests) = mpsc::channel::<()>();
+                let sender = sender.clone();
+                let jobserver = jobserver.clone();
+                let done = done.clone();
+
+                let acquirer =
+                    thread::spawn(move || acquire_tokens(jobserver, thread_requests, sender, done));
+                (Some(requests), Some(acquirer))
             }
+            None => (None, None),
+        };
 
-            let thread_id = result.unwrap();
-            free_threads.insert(thread_id);
-            trace!("thread {} is idle", thread_id);
+        // Wake up periodically to notice when the load average drops.
+        if self.max_load.is_some() {
+            if load_average().is_some() {
+                let sender = sender.clone();
 
-            // If the thread was previously running a task, mark it as completed.
-            if let Some(task) = current_tasks.remove(&thread_id) {
-                trace!("task '{}' completed", task);
-                completed_tasks.insert(task);
+                thread::spawn(move || {
+                    loop {
+                        thread::sleep(Duration::from_millis(LOAD_CHECK_INTERVAL_MS));
+                        if sender.send(Message::Tick).is_err() {
+                            break;
+                        }
+                    }
+                });
+            } else {
+                warn!("the load average is not available on this system; ignoring --max-load");
             }
+        }
 
-            // Attempt to schedule more tasks to run. The most we can schedule is the number of free
-            // threads, but it is limited by the number of tasks that have their dependencies already
-            // finished.
-            'schedule: for _ in 0..free_threads.len() {
-                // If the queue is empty, we are done.
-                if queue.is_empty() {
+        drop(sender);
+
+        // Keep track of idle threads and of the number of tasks started so far.
+        let mut free_threads: Vec<usize> = Vec::new();
+        let mut started = 0;
+        let mut running = 0;
+        let mut alive = thread_count;
+        let mut interrupted = false;
+        let mut summary = Summary::default();
+
+        // Jobserver tokens held for running tasks, and whether a token has been requested.
+        let mut tokens = jobserver.as_ref().map(|jobserver| Tokens::new(jobserver.clone()));
+        let mut requested = false;
+
+        // Indicates if new tasks are being held back because the load average is too high.
+        let mut overloaded = false;
+
+        // Set when a task fails without keep going enabled, or when interrupted. No new tasks are
+        // started after that, but tasks that are already running are allowed to finish.
+        let mut stopping = false;
+
+        while running > 0 || (!stopping && !scheduler.is_finished()) {
+            // Wait for a thread to become idle.
+            let message = match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => {
+                    error!("all worker threads stopped unexpectedly");
+                    stopping = true;
                     break;
                 }
+            };
 
-                // Check the next task in the queue. If any of its dependencies have not yet been
-                // completed, we cannot schedule it yet.
-                for dependency in queue.front().unwrap().dependencies() {
-                    // Check that the dependency needs scheduled at all (some are already satisfied),
-                    // and that it hasn't already finished.
-                    if all_tasks.contains(dependency) && !completed_tasks.contains(dependency) {
-                        // We can't run the next task, so we're done scheduling for now until another
-                        // thread finishes.
-                        break 'schedule;
+            match message {
+                Message::Idle(thread_id) => {
+                    trace!("thread {} is idle", thread_id);
+                    free_threads.push(thread_id);
+                }
+                Message::Stopped(thread_id) => {
+                    debug!("thread {} stopped", thread_id);
+                    alive -= 1;
+                }
+                Message::Tick => {}
+                Message::Token(token) => {
+                    trace!("acquired jobserver token");
+                    if let Some(ref mut tokens) = tokens {
+                        tokens.push(token);
+                    }
+                    requested = false;
+                }
+                Message::Interrupted => {
+                    warn!("interrupted; stopping {} running task(s)", running);
+                    interrupted = true;
+                    stopping = true;
+                }
+                Message::Completed(operation, reply) => {
+                    if let Some((ref mut executor, ref sender)) = coroutines {
+                        if let Some(finished) = executor.resume(operation, reply) {
+                            let _ = sender.send(finished.into());
+                        }
                     }
                 }
+                Message::Finished { thread, task, duration, attempts, result } => {
+                    running -= 1;
+                    free_threads.push(thread);
+
+                    match result {
+                        Ok(()) => {
+                            trace!("task '{}' completed", task);
+                            if !self.spec.dry_run {
+                                self.spec
+                                    .database
+                                    .lock()
+                                    .unwrap()
+                                    .set_duration(task.as_str(), duration);
+                            }
 
-                // Get the available task from the queue.
-                let task = queue.front().unwrap().clone();
+                            if attempts > 1 {
+                                summary.retried.push((task.clone(), attempts));
+                            }
 
-                // Pick a free thread to run the task in.
-                if let Some(thread_id) = free_threads.iter().next().map(|t| *t) {
-                    trace!("scheduling task '{}' on thread {}", task.name(), thread_id);
-                    let data = (task.name().to_string(), task_count - queue.len() + 1);
+                            scheduler.complete(&task);
+                            summary.succeeded.push(task);
+                        }
+                        // Tasks that fail after an interrupt were most likely stopped by it.
+                        // Their outputs may be incomplete, so remove them.
+                        Err(ref e) if interrupted => {
+                            debug!("task '{}' was cancelled: {}", task, e);
+                            if let Ok(task) = self.graph.get(&task) {
+                                remove_outputs(&*task);
+                            }
+                            summary.cancelled.push(task);
+                        }
+                        Err(e) => {
+                            error!("task '{}' failed: {}", task, e);
+
+                            // With keep going enabled, only the tasks that depend on the failed
+                            // task are skipped. Otherwise, stop starting new tasks altogether.
+                            if self.spec.keep_going {
+                                for skipped in scheduler.fail(&task) {
+                                    debug!("skipping task '{}' because '{}' failed",
+                                           skipped,
+                                           task);
+                                    summary.skipped.push(skipped);
+                                }
+                            } else if !stopping {
+                                if running > 0 {
+                                    info!("waiting for {} running task(s) to finish", running);
+                                }
+                                stopping = true;
+                            }
 
-                    // Send the task name.
-                    if channels[thread_id].send(data).is_ok() {
-                        current_tasks.insert(thread_id, task.name().to_string());
-                        free_threads.remove(&thread_id);
+                            summary.failed.push(task);
+                        }
+                    }
+                }
+            }
 
-                        // Scheduling was successful, so remove the task frome the queue.
-                        queue.pop_front().unwrap();
-                    } else {
-                        trace!("failed to send channel to thread {}", thread_id);
+            if !stopping {
+                // Hand out as many ready tasks as there are free threads. Any task in the ready
+                // set can be run, so a task waiting on its dependencies never holds up the others.
+                // Tasks waiting for room in their pool are left in the ready set.
+                while !free_threads.is_empty() && scheduler.has_ready() {
+                    // Do not add to the load of an overloaded system, unless nothing is running.
+                    if let Some(max_load) = self.max_load {
+                        let load = load_average().unwrap_or(0.0);
+
+                        if running > 0 && load > max_load {
+                            if !overloaded {
+                                debug!("load average {:.2} is above {}; waiting", load, max_load);
+                                overloaded = true;
+                            }
+                            break;
+                        }
+
+                        overloaded = false;
                     }
-                } else {
-                    // We can schedule now, but there aren't any free threads. 😢
-                    break;
+
+                    // Every running task besides the first needs a token from the jobserver.
+                    if let (Some(requests), Some(tokens)) = (token_requests.as_ref(),
+                                                             tokens.as_ref()) {
+                        if running > tokens.len() {
+                            if !requested {
+                                requested = requests.send(()).is_ok();
+                            }
+                            break;
+                        }
+                    }
+
+                    let task = match scheduler.next() {
+                        Some(task) => task,
+                        None => break,
+                    };
+                    let thread_id = free_threads.pop().unwrap();
+                    started += 1;
+
+                    trace!("scheduling task '{}' on thread {}", task.name(), thread_id);
+                    if let Some((ref mut executor, ref sender)) = coroutines {
+                        info!("running task '{}' ({} of {})", task.name(), started, task_count);
+
+                        let finished = if self.spec.dry_run {
+                            info!("would run task '{}'", task.name());
+                            Some(Finished {
+                                slot: thread_id,
+                                task: task.name().to_string(),
+                                duration: Duration::from_secs(0),
+                                attempts: 1,
+                                result: Ok(()),
+                            })
+                        } else {
+                            executor.start(thread_id, task.clone())
+                        };
+
+                        // Handle tasks that finish right away like any other finished task.
+                        if let Some(finished) = finished {
+                            let _ = sender.send(finished.into());
+                        }
+                    } else if channels[thread_id].send((task.name().to_string(), started))
+                                                 .is_err() {
+                        return Err(format!("failed to send task to thread {}", thread_id).into());
+                    }
+
+                    running += 1;
+                }
+
+                // If nothing is running and nothing can be run, the remaining tasks can never
+                // finish.
+                if running == 0 && (alive == 0 || !scheduler.has_ready()) &&
+                   !scheduler.is_finished() {
+                    error!("some tasks could not be scheduled");
+                    stopping = true;
+                }
+            }
+
+            // Give back any tokens that are no longer needed, so that other processes sharing the
+            // jobserver can use them.
+            if let Some(ref mut tokens) = tokens {
+                tokens.release_to(cmp::max(running, 1) - 1);
+            }
+        }
+
+        // Stop acquiring tokens. The tokens held are given back first, in case the thread
+        // acquiring them is waiting for one.
+        done.store(true, Ordering::SeqCst);
+        drop(tokens);
+        drop(token_requests);
+
+        if let Some(acquirer) = acquirer {
+            if let Err(e) = acquirer.join() {
+                trace!("token thread closed with panic: {:?}", e);
+            }
+        }
+
+        // Give back any tokens that were acquired after the last task finished, too.
+        if let Some(ref jobserver) = jobserver {
+            let mut tokens = Tokens::new(jobserver.clone());
+            while let Ok(message) = receiver.try_recv() {
+                if let Message::Token(token) = message {
+                    tokens.push(token);
                 }
             }
         }
 
         // Close the input and wait for any remaining threads to finish.
         drop(channels);
+
+        if let Err(e) = watcher.join() {
+            trace!("signal watcher closed with panic: {:?}", e);
+        }
+
         for (thread_id, thread) in threads.into_iter().enumerate() {
             if let Err(e) = thread.join() {
                 trace!("thread {} closed with panic: {:?}", thread_id, e);
             }
         }
 
-        info!("all tasks up to date");
-        Ok(())
+        try!(self.save_database());
+
+        if let (Some(trace), Some(path)) = (self.spec.trace.as_ref(), self.trace_path.as_ref()) {
+            if let Err(e) = trace.save(path) {
+                warn!("failed to write trace: {}", e);
+            }
+        }
+
+        // Tasks that were never started because the run stopped early are also skipped.
+        let not_started = task_count - summary.succeeded.len() - summary.failed.len() -
+                          summary.cancelled.len() - summary.skipped.len();
+        summary.print(not_started);
+
+        if !stopping && summary.failed.is_empty() {
+            info!("all tasks up to date");
+            return Ok(());
+        }
+
+        if interrupted {
+            Err("interrupted".into())
+        } else {
+            Err("not all tasks completed successfully".into())
+        }
+    }
+
+    /// Filters a schedule down to the tasks affected by a set of changed files.
+    ///
+    /// A task is affected if one of its dependencies or outputs changed, if a file matching one of
+    /// its watch patterns changed, or if it depends on another affected task.
+    fn affected(&self,
+                queue: VecDeque<Rc<Task>>,
+                changed: &HashSet<String>)
+                -> VecDeque<Rc<Task>> {
+        let mut affected = HashSet::new();
+        let mut schedule = VecDeque::new();
+
+        // The schedule is in dependency order, so dependencies are always checked first.
+        for task in queue {
+            let outputs_changed = changed.contains(task.name()) ||
+                                  task.outputs().iter().any(|output| changed.contains(output));
+
+            let dependencies_changed = task.dependencies().iter().any(|dependency| {
+                changed.contains(dependency) ||
+                self.graph
+                    .get(dependency)
+                    .map(|dependency| affected.contains(dependency.name()))
+                    .unwrap_or(false)
+            });
+
+            let watched_changed = task.watch_patterns().iter().any(|pattern| {
+                glob::Pattern::new(pattern)
+                    .map(|pattern| changed.iter().any(|path| pattern.matches(path)))
+                    .unwrap_or(false)
+            });
+
+            if outputs_changed || dependencies_changed || watched_changed {
+                affected.insert(task.name().to_string());
+                schedule.push_back(task);
+            }
+        }
+
+        schedule
+    }
+
+    /// Creates a watcher for every file in the task graph, along with any additional files that
+    /// tasks asked to watch.
+    fn watcher(&self) -> Watcher {
+        let mut watcher = Watcher::new();
+        watcher.add_path(self.path().to_string_lossy().into_owned());
+
+        for task in self.graph.tasks() {
+            for path in task.dependencies() {
+                if Path::new(path).exists() {
+                    watcher.add_path(path.clone());
+                }
+            }
+
+            for path in task.outputs() {
+                if Path::new(path).exists() {
+                    watcher.add_output(path.clone());
+                }
+            }
+
+            if Path::new(task.name()).exists() {
+                if task.kind() == Kind::File {
+                    watcher.add_output(task.name());
+                } else {
+                    watcher.add_path(task.name());
+                }
+            }
+
+            for pattern in task.watch_patterns() {
+                if let Err(e) = watcher.add_pattern(pattern.clone()) {
+                    warn!("invalid watch pattern '{}': {}", pattern, e);
+                }
+            }
+        }
+
+        watcher
+    }
+
+    /// Gets the jobserver used to limit the number of tasks running at once, creating it if needed.
+    ///
+    /// The jobserver is shared with child processes, such as `make`, so that they do not run more
+    /// jobs than allowed in total. When run by make, its jobserver is used instead of creating our
+    /// own.
+    fn jobserver(&mut self) -> Option<Arc<Jobserver>> {
+        if self.jobserver.is_none() {
+            self.jobserver = match Jobserver::from_env() {
+                Some(jobserver) => {
+                    debug!("using jobserver of parent make process");
+                    Some(Arc::new(jobserver))
+                }
+                None => {
+                    match Jobserver::new(self.jobs) {
+                        Ok(jobserver) => {
+                            jobserver.export();
+                            Some(Arc::new(jobserver))
+                        }
+                        Err(e) => {
+                            debug!("not using a jobserver: {}", e);
+                            None
+                        }
+                    }
+                }
+            };
+        }
+
+        self.jobserver.clone()
     }
 
-    fn resolve_task<S: AsRef<str>>(&mut self, name: S) -> Result<(), Box<Error>> {
+    /// Writes any changes made to the build database to disk.
+    fn save_database(&self) -> Result<(), Box<Error>> {
+        self.spec.database.lock().unwrap().save()
+    }
+
+    /// Resolves a task and all of its dependencies, adding them to the graph.
+    ///
+    /// If `needed_by` is given, it is the name of the task that depends on the task being
+    /// resolved. Only dependencies can be satisfied by plain source files.
+    fn resolve_task<S: AsRef<str>>(&mut self,
+                                   name: S,
+                                   needed_by: Option<&str>)
+                                   -> Result<(), Box<Error>> {
         if !self.graph.contains(&name) {
             // Lookup the task to run.
             if let Some(task) = self.runtime().environment().get_task(&name) {
                 debug!("task '{}' matches named task", name.as_ref());
                 self.graph.insert(task.clone());
             }
-            // Find a rule that matches the task name.
-            else if let Some(rule) = self.runtime()
-                .environment()
-                .rules()
-                .iter()
-                .find(|rule| rule.matches(&name)) {
-                debug!("task '{}' matches rule '{}'", name.as_ref(), rule.pattern);
+            // Find the rule that best matches the task name.
+            else if let Some((rule, ties)) = self.runtime().environment().find_rule(&name) {
+                debug!("task '{}' matches rule '{}'", name.as_ref(), rule);
+
+                if !ties.is_empty() {
+                    let others: Vec<_> = ties.iter().map(|rule| format!("'{}'", rule)).collect();
+                    warn!("'{}' matches more than one rule equally well; using rule '{}' because \
+                           it was defined first, ignoring {}",
+                          name.as_ref(),
+                          rule,
+                          others.join(", "));
+                }
+
                 // Create a task for the rule and insert it in the graph.
                 self.graph.insert(Rc::new(rule.create_task(name.as_ref()).unwrap()));
             }
+            // Dependencies that are existing files do not need to be produced by anything.
+            else if needed_by.is_some() && Path::new(name.as_ref()).exists() {
+                trace!("task '{}' is a source file", name.as_ref());
+                self.graph.insert(Rc::new(SourceFile::new(name.as_ref())));
+            }
             // No matching task.
-            else {
+            else if let Some(needed_by) = needed_by {
+                return Err(format!("no rule to make '{}', needed by '{}'",
+                                   name.as_ref(),
+                                   needed_by)
+                    .into());
+            } else {
                 return Err(format!("no matching task or rule for '{}'", name.as_ref()).into());
             }
         }
 
-        for dependency in self.graph.get(name).unwrap().dependencies() {
+        for dependency in self.graph.get(&name).unwrap().dependencies() {
             if !self.graph.contains(dependency) {
-                try!(self.resolve_task(dependency));
+                try!(self.resolve_task(dependency, Some(name.as_ref())));
             }
         }
 
         Ok(())
     }
 
+    /// Checks that every named task in the graph can be run with the arguments given to it, so
+    /// that missing or invalid arguments are reported before anything runs.
+    fn check_arguments(&self) -> Result<(), Box<Error>> {
+        for name in self.spec.arguments.keys() {
+            if self.runtime().environment().get_task(name).is_none() {
+                return Err(format!("'{}' is not a named task and does not take arguments", name)
+                    .into());
+            }
+        }
+
+        for task in self.graph.tasks() {
+            if let Some(task) = self.runtime().environment().get_task(task.name()) {
+                let arguments = self.spec
+                    .arguments
+                    .get(task.name())
+                    .cloned()
+                    .unwrap_or(Arguments::default());
+
+                try!(task.bind(&arguments));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves every named task, so that the graph covers everything the Rotefile can build.
+    ///
+    /// Tasks that cannot be resolved are reported and left out.
+    fn resolve_all(&mut self) {
+        for task in self.runtime().environment().tasks() {
+            if let Err(e) = self.resolve_task(task.name(), None) {
+                warn!("{}", e);
+            }
+        }
+    }
+
     fn runtime(&self) -> Runtime {
         self.runtime.as_ref().unwrap().clone()
     }
 }
+
+/// Watches for interrupt signals while tasks are running, until `done` is set.
+///
+/// When a signal is received, it is forwarded to every running child process and the master thread
+/// is notified. Any children that are still running after a grace period are killed.
+fn watch_signals(sender: mpsc::Sender<Message>, processes: Arc<Processes>, done: Arc<AtomicBool>) {
+    while !processes::interrupted() {
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    if let Some(signal) = processes::signal() {
+        processes.signal_all(signal);
+    }
+
+    if sender.send(Message::Interrupted).is_err() {
+        return;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(GRACE_PERIOD_SECS);
+    while Instant::now() < deadline {
+        if done.load(Ordering::SeqCst) || processes.len() == 0 {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    warn!("killing {} process(es) that did not exit after {} seconds",
+          processes.len(),
+          GRACE_PERIOD_SECS);
+    processes.kill_all();
+}
+
+/// Acquires a token from the jobserver for each request received, sending it to the master thread,
+/// until `done` is set.
+fn acquire_tokens(jobserver: Arc<Jobserver>,
+                  requests: mpsc::Receiver<()>,
+                  sender: mpsc::Sender<Message>,
+                  done: Arc<AtomicBool>) {
+    while requests.recv().is_ok() {
+        let mut token = None;
+
+        // Wait for a token in short steps, so that waiting stops when the run ends.
+        while token.is_none() {
+            if done.load(Ordering::SeqCst) {
+                return;
+            }
+
+            token = match jobserver.try_acquire(Duration::from_millis(TOKEN_WAIT_MS)) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("failed to acquire jobserver token: {}", e);
+                    return;
+                }
+            };
+        }
+
+        // If the run has already finished, the token must be given back.
+        let token = token.unwrap();
+        if done.load(Ordering::SeqCst) || sender.send(Message::Token(token)).is_err() {
+            let _ = jobserver.release(token);
+            return;
+        }
+    }
+}
+
+/// Runs a task, running it again if it fails as many times as the task allows.
+///
+/// Returns the result of the last attempt, and the number of attempts made.
+fn run_with_retries(task: &Task,
+                    runtime: &Runtime,
+                    spec: &EnvironmentSpec)
+                    -> (Result<(), String>, u32) {
+    let timeout = task.timeout().or(spec.timeout);
+    let attempts = task.retries() + 1;
+    let mut attempt = 1;
+
+    loop {
+        let result = run_task(task, timeout, runtime, spec);
+
+        if result.is_ok() || attempt == attempts || processes::interrupted() {
+            return (result, attempt);
+        }
+
+        warn!("task '{}' failed on attempt {} of {}: {}",
+              task.name(),
+              attempt,
+              attempts,
+              result.unwrap_err());
+
+        thread::sleep(task.retry_delay());
+        attempt += 1;
+        info!("retrying task '{}' (attempt {} of {})", task.name(), attempt, attempts);
+    }
+}
+
+/// Runs a task, stopping it if it runs for longer than the given timeout.
+fn run_task(task: &Task,
+            timeout: Option<Duration>,
+            runtime: &Runtime,
+            spec: &EnvironmentSpec)
+            -> Result<(), String> {
+    let processes = &spec.processes;
+
+    // Tasks in the console pool have the terminal to themselves.
+    processes.set_foreground(task.name(), task.pool() == Some(CONSOLE_POOL));
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return task.run().map_err(|e| e.to_string()),
+    };
+
+    // The processes of a previous attempt may have been killed.
+    processes.resume(task.name());
+
+    let deadline = Deadline::start(task.name(), timeout, processes, &spec.timer);
+    deadline.set_interrupt(Some(runtime::interrupt(&runtime.state())));
+    let result = task.run();
+
+    try!(deadline.finish());
+    result.map_err(|e| e.to_string())
+}
+
+/// Gets the system load average over the last minute, if available.
+fn load_average() -> Option<f64> {
+    let mut contents = String::new();
+
+    match File::open("/proc/loadavg").and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => contents.split_whitespace().next().and_then(|load| load.parse().ok()),
+        Err(_) => None,
+    }
+}
+
+/// Deletes the outputs of a task that was interrupted, since they may be incomplete.
+fn remove_outputs(task: &Task) {
+    for output in task.outputs() {
+        if fs::remove_file(output).is_ok() {
+            info!("removed incomplete output '{}'", output);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use lua::ffi;
+use reaper::{Process, Reaper};
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::ptr;
+use std::sync::Arc;
+use task::Value;
+
+
+/// The values produced by an operation a task waited for, or an error message.
+pub type Reply = Result<Vec<Value>, String>;
+
+/// Receives the ID and reply of each deferred operation once it finishes.
+pub type Notify = Arc<Fn(usize, Reply) + Send + Sync>;
+
+/// Outcome of a task function that ran as a coroutine, or an error message.
+pub type Outcome = Result<(), String>;
+
+/// State shared between the coroutine executor and the functions called by tasks.
+///
+/// When the executor is not in use, tasks call their functions directly and functions such as
+/// `exec` wait in place, so none of this state is used.
+pub struct Coroutines {
+    /// Receives the replies of deferred operations, if the executor is in use.
+    notify: RefCell<Option<Notify>>,
+
+    /// Waits for the child processes of deferred operations.
+    reaper: RefCell<Option<Reaper>>,
+
+    /// ID of the next operation.
+    next_operation: Cell<usize>,
+
+    /// ID of the operation most recently deferred, not yet picked up by the executor.
+    deferred: Cell<Option<usize>>,
+
+    /// The coroutine of the task currently running, or null.
+    current: Cell<*mut ffi::lua_State>,
+}
+
+impl Coroutines {
+    pub fn new() -> Coroutines {
+        Coroutines {
+            notify: RefCell::new(None),
+            reaper: RefCell::new(None),
+            next_operation: Cell::new(0),
+            deferred: Cell::new(None),
+            current: Cell::new(ptr::null_mut()),
+        }
+    }
+
+    /// Enables deferring operations, waiting for their processes with the given reaper and sending
+    /// their replies to the given callback.
+    pub fn set_notify(&self, notify: Notify, reaper: Reaper) {
+        *self.notify.borrow_mut() = Some(notify);
+        *self.reaper.borrow_mut() = Some(reaper);
+    }
+
+    /// Sets the coroutine of the task currently running.
+    ///
+    /// Any operation deferred before is forgotten, so that only operations deferred by the
+    /// coroutine itself are taken by `take_deferred()`.
+    pub fn set_current(&self, state: *mut ffi::lua_State) {
+        self.current.set(state);
+        self.deferred.set(None);
+    }
+
+    /// Checks if a function called from the given Lua state may defer an operation and yield.
+    ///
+    /// This is only the case when called directly by a task running as a coroutine, and not from
+    /// another coroutine created by the task or from somewhere that cannot yield.
+    pub fn can_defer(&self, state: *mut ffi::lua_State) -> bool {
+        self.notify.borrow().is_some() && !state.is_null() && state == self.current.get() &&
+        unsafe { ffi::lua_isyieldable(state) != 0 }
+    }
+
+    /// Gets a new operation ID, for an operation that finishes some other way than by deferring,
+    /// such as waiting for a delay.
+    pub fn new_operation(&self) -> usize {
+        let id = self.next_operation.get();
+        self.next_operation.set(id + 1);
+        id
+    }
+
+    /// Waits for a child process in the background, returning the ID of the operation.
+    ///
+    /// Once the child exits, `reply` is called with its exit code on the reaper thread, and the
+    /// reply is passed to the callback given to `set_notify()`.
+    pub fn defer<F>(&self, process: Process, reply: F) -> usize
+        where F: FnOnce(io::Result<Option<i32>>) -> Reply + Send + 'static
+    {
+        let id = self.new_operation();
+        self.deferred.set(Some(id));
+
+        let notify = self.notify.borrow().clone().expect("operations cannot be deferred");
+        let mut reply = Some(reply);
+
+        self.reaper
+            .borrow()
+            .as_ref()
+            .expect("operations cannot be deferred")
+            .watch(process,
+                   Box::new(move |status| if let Some(reply) = reply.take() {
+                       notify(id, reply(status));
+                   }));
+
+        id
+    }
+
+    /// Takes the ID of the operation most recently deferred.
+    pub fn take_deferred(&self) -> Option<usize> {
+        let id = self.deferred.get();
+        self.deferred.set(None);
+        id
+    }
+}
@@ -1,11 +1,20 @@
+use database::{CommandLine, Database};
+use output::{Buffer, Output};
+use processes::Processes;
+use runtime::{Coroutines, Scope};
 use rule::Rule;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
+use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use task::{Task, NamedTask};
+use std::sync::{Arc, Mutex};
+use task::{self, Arguments, Task, NamedTask};
+use trace::Trace;
 
 
 /// Stores the state of an entire task execution environment.
@@ -16,17 +25,56 @@ pub struct Environment {
     /// A vector of all defined file rules.
     rules: RefCell<Vec<Rc<Rule>>>,
 
+    /// The depth of each defined pool.
+    pools: RefCell<HashMap<String, usize>>,
+
     /// The default task to run.
     default_task: RefCell<Option<String>>,
 
     /// The name of the task currently running.
     current_task: RefCell<Option<String>>,
 
+    /// Command lines executed by the task currently running.
+    commands: RefCell<Vec<CommandLine>>,
+
     /// Full path to the current script.
     path: PathBuf,
 
     /// Directory of the current script.
     directory: PathBuf,
+
+    /// The build database shared between all environments.
+    database: Arc<Mutex<Database>>,
+
+    /// Registry of child processes shared between all environments.
+    processes: Arc<Processes>,
+
+    /// Indicates if all rules should use checksums by default.
+    checksums: Cell<bool>,
+
+    /// Handles the output of tasks.
+    output: Output,
+
+    /// Timeline that commands run are recorded in, if enabled.
+    trace: RefCell<Option<Trace>>,
+
+    /// Arguments given to tasks on the command line, keyed by task name.
+    arguments: RefCell<HashMap<String, Arguments>>,
+
+    /// The scope of the Rotefile currently being loaded or of the task currently running.
+    scope: RefCell<Scope>,
+
+    /// The scope each task was defined in, keyed by task name.
+    task_scopes: RefCell<HashMap<String, Scope>>,
+
+    /// The scope each rule was defined in, in the same order as the rules.
+    rule_scopes: RefCell<Vec<Scope>>,
+
+    /// Full paths of every Rotefile included so far.
+    included: RefCell<HashSet<PathBuf>>,
+
+    /// State used when tasks run as coroutines.
+    coroutines: Coroutines,
 }
 
 impl Environment {
@@ -34,7 +82,10 @@ impl Environment {
     ///
     /// The instance is placed inside a box to ensure the runner has a constant location in memory
     /// so that it can be referenced by native closures in the runtime.
-    pub fn new<P: Into<PathBuf>>(script: P) -> Result<Environment, Box<Error>> {
+    pub fn new<P: Into<PathBuf>>(script: P,
+                                 database: Arc<Mutex<Database>>,
+                                 processes: Arc<Processes>)
+                                 -> Result<Environment, Box<Error>> {
         let script = script.into();
         let directory = match script.parent() {
             Some(path) => path.into(),
@@ -43,13 +94,30 @@ impl Environment {
             }
         };
 
+        // The console pool is always available.
+        let mut pools = HashMap::new();
+        pools.insert(task::CONSOLE_POOL.to_string(), 1);
+
         Ok(Environment {
             tasks: RefCell::new(HashMap::new()),
             rules: RefCell::new(Vec::new()),
+            pools: RefCell::new(pools),
             default_task: RefCell::new(None),
             current_task: RefCell::new(None),
+            commands: RefCell::new(Vec::new()),
             path: script,
             directory: directory,
+            database: database,
+            processes: processes,
+            checksums: Cell::new(false),
+            output: Output::new(),
+            trace: RefCell::new(None),
+            arguments: RefCell::new(HashMap::new()),
+            scope: RefCell::new(Scope::default()),
+            task_scopes: RefCell::new(HashMap::new()),
+            rule_scopes: RefCell::new(Vec::new()),
+            included: RefCell::new(HashSet::new()),
+            coroutines: Coroutines::new(),
         })
     }
 
@@ -63,6 +131,82 @@ impl Environment {
         &self.directory
     }
 
+    /// Gets the shared build database.
+    pub fn database(&self) -> Arc<Mutex<Database>> {
+        self.database.clone()
+    }
+
+    /// Gets the shared registry of child processes.
+    pub fn processes(&self) -> Arc<Processes> {
+        self.processes.clone()
+    }
+
+    /// Gets the handler for the output of tasks.
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    /// Gets the timeline that commands run are recorded in, if enabled.
+    pub fn trace(&self) -> Option<Trace> {
+        self.trace.borrow().clone()
+    }
+
+    /// Sets the timeline that commands run are recorded in.
+    pub fn set_trace(&self, trace: Option<Trace>) {
+        *self.trace.borrow_mut() = trace;
+    }
+
+    /// Gets the arguments given to a task on the command line.
+    pub fn arguments(&self, task: &str) -> Arguments {
+        self.arguments.borrow().get(task).cloned().unwrap_or(Arguments::default())
+    }
+
+    /// Sets the arguments given to each task on the command line.
+    pub fn set_arguments(&self, arguments: HashMap<String, Arguments>) {
+        *self.arguments.borrow_mut() = arguments;
+    }
+
+    /// Gets the scope of the Rotefile currently being loaded or of the task currently running.
+    pub fn scope(&self) -> Scope {
+        self.scope.borrow().clone()
+    }
+
+    /// Sets the current scope, returning the previous one.
+    pub fn set_scope(&self, scope: Scope) -> Scope {
+        mem::replace(&mut *self.scope.borrow_mut(), scope)
+    }
+
+    /// Gets the directory that commands are run in for the current scope.
+    pub fn working_dir(&self) -> io::Result<PathBuf> {
+        let scope = self.scope.borrow();
+
+        env::current_dir().map(|dir| if scope.directory.is_empty() {
+            dir
+        } else {
+            dir.join(&scope.directory)
+        })
+    }
+
+    /// Resolves a path given in the current scope to a path relative to the root Rotefile.
+    pub fn resolve(&self, path: &str) -> String {
+        self.scope.borrow().file_path(path)
+    }
+
+    /// Records that a Rotefile has been included. Returns false if it was already included.
+    pub fn mark_included<P: Into<PathBuf>>(&self, path: P) -> bool {
+        self.included.borrow_mut().insert(path.into())
+    }
+
+    /// Checks if rules should use checksums instead of modification times by default.
+    pub fn checksums(&self) -> bool {
+        self.checksums.get()
+    }
+
+    /// Sets whether rules should use checksums instead of modification times by default.
+    pub fn set_checksums(&self, checksums: bool) {
+        self.checksums.set(checksums);
+    }
+
     /// Gets a list of all registered tasks.
     pub fn tasks(&self) -> Vec<Rc<NamedTask>> {
         self.tasks.borrow().values().map(|rc| rc.clone()).collect()
@@ -75,15 +219,114 @@ impl Environment {
 
     /// Creates a new task.
     pub fn create_task(&self, task: NamedTask) {
+        self.task_scopes.borrow_mut().insert(task.name().into(), self.scope());
+
         // Add it to the master list of tasks.
         self.tasks.borrow_mut().insert(task.name().into(), Rc::new(task));
     }
 
     /// Creates a new rule.
     pub fn create_rule(&self, rule: Rule) {
+        self.rule_scopes.borrow_mut().push(self.scope());
         self.rules.borrow_mut().push(Rc::new(rule));
     }
 
+    /// Resolves the dependencies of every task and rule to full task names and file paths.
+    ///
+    /// This has to wait until every Rotefile has been loaded, since a dependency can name a task
+    /// that is defined further down or in another file. Fails if a task or rule is already in use,
+    /// such as by a task that was run while the Rotefile was loading.
+    pub fn qualify_dependencies(&self) -> Result<(), Box<Error>> {
+        let names: HashSet<String> = self.tasks.borrow().keys().cloned().collect();
+        let is_task = |name: &str| names.contains(name);
+
+        for (name, task) in self.tasks.borrow_mut().iter_mut() {
+            let scope = match self.task_scopes.borrow().get(name) {
+                Some(scope) => scope.clone(),
+                None => continue,
+            };
+            let dependencies: Vec<String> = task.dependencies
+                .iter()
+                .map(|dependency| scope.qualify(dependency, &is_task))
+                .collect();
+
+            if dependencies != task.dependencies {
+                match Rc::get_mut(task) {
+                    Some(task) => task.dependencies = dependencies,
+                    None => {
+                        return Err(format!("cannot resolve the dependencies of task '{}' while \
+                                            it is in use",
+                                           name)
+                            .into());
+                    }
+                }
+            }
+        }
+
+        let scopes = self.rule_scopes.borrow();
+        for (rule, scope) in self.rules.borrow_mut().iter_mut().zip(scopes.iter()) {
+            let inputs: Vec<String> = rule.inputs()
+                .iter()
+                .map(|input| scope.qualify(input, &is_task))
+                .collect();
+
+            if inputs.as_slice() != rule.inputs() {
+                let pattern = rule.to_string();
+
+                match Rc::get_mut(rule) {
+                    Some(rule) => rule.set_inputs(inputs),
+                    None => {
+                        return Err(format!("cannot resolve the dependencies of rule '{}' while \
+                                            it is in use",
+                                           pattern)
+                            .into());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Defines a pool, or changes the depth of an existing pool.
+    pub fn create_pool<S: Into<String>>(&self, name: S, depth: usize) {
+        self.pools.borrow_mut().insert(name.into(), depth);
+    }
+
+    /// Gets the depth of each defined pool.
+    pub fn pools(&self) -> HashMap<String, usize> {
+        self.pools.borrow().clone()
+    }
+
+    /// Finds the most specific rule that matches a file name.
+    ///
+    /// If several rules match the file equally well, the one defined first is chosen. The other
+    /// equally good rules are returned alongside the chosen rule so that the ambiguity can be
+    /// reported.
+    pub fn find_rule<S: AsRef<str>>(&self, name: S) -> Option<(Rc<Rule>, Vec<Rc<Rule>>)> {
+        let mut candidates: Vec<_> = self.rules
+            .borrow()
+            .iter()
+            .filter_map(|rule| rule.specificity(name.as_ref()).map(|s| (s, rule.clone())))
+            .collect();
+
+        // Sort from most to least specific. The sort is stable, so equally specific rules stay in
+        // the order they were defined.
+        candidates.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut candidates = candidates.into_iter();
+        let (specificity, rule) = match candidates.next() {
+            Some(candidate) => candidate,
+            None => return None,
+        };
+
+        let ties = candidates.take_while(|&(s, _)| s == specificity)
+            .map(|(_, rule)| rule)
+            .collect();
+
+        Some((rule, ties))
+    }
+
     /// Gets a task by name.
     pub fn get_task<S: AsRef<str>>(&self, name: S) -> Option<Rc<NamedTask>> {
         self.tasks.borrow().get(name.as_ref()).map(|rc| rc.clone())
@@ -113,10 +356,55 @@ impl Environment {
     /// Sets the currently running task.
     pub fn set_current_task<S: Into<String>>(&self, name: S) {
         *self.current_task.borrow_mut() = Some(name.into());
+        self.commands.borrow_mut().clear();
     }
 
     /// Clears the currently running task.
     pub fn clear_current_task(&self) {
         *self.current_task.borrow_mut() = None;
     }
+
+    /// Records a command line executed by the currently running task.
+    pub fn record_command(&self, command: CommandLine) {
+        self.commands.borrow_mut().push(command);
+    }
+
+    /// Takes the list of command lines executed since the current task was started.
+    pub fn take_commands(&self) -> Vec<CommandLine> {
+        self.commands.borrow_mut().drain(..).collect()
+    }
+
+    /// Replaces the context of the task currently running, returning the old one.
+    pub fn swap_context(&self, context: Context) -> Context {
+        Context {
+            task: mem::replace(&mut *self.current_task.borrow_mut(), context.task),
+            commands: mem::replace(&mut *self.commands.borrow_mut(), context.commands),
+            scope: mem::replace(&mut *self.scope.borrow_mut(), context.scope),
+            output: self.output.swap_buffer(context.output),
+        }
+    }
+
+    /// Gets the state used when tasks run as coroutines.
+    pub fn coroutines(&self) -> &Coroutines {
+        &self.coroutines
+    }
+}
+
+/// The parts of an environment that belong to the task currently running.
+///
+/// Tasks running as coroutines take turns in the same environment, so each keeps its own context
+/// that is swapped in whenever it runs.
+#[derive(Default)]
+pub struct Context {
+    /// The name of the task.
+    pub task: Option<String>,
+
+    /// Command lines executed by the task.
+    pub commands: Vec<CommandLine>,
+
+    /// The scope the task runs in.
+    pub scope: Scope,
+
+    /// Output collected for the task in grouped mode.
+    pub output: Buffer,
 }
@@ -5,14 +5,20 @@ use std::clone::Clone;
 use std::error::Error;
 use std::hash::{Hash, Hasher, SipHasher};
 use std::mem;
-use std::path::PathBuf;
+use processes::Interrupt;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::rc::{Rc, Weak};
+use task::Value;
 
+mod coroutines;
 mod environment;
 mod iter;
+mod scope;
 
-pub use self::environment::Environment;
+pub use self::coroutines::{Coroutines, Notify, Outcome, Reply};
+pub use self::environment::{Context, Environment};
+pub use self::scope::{normalize, Scope};
 
 /// Results that are returned by functions callable from Lua.
 pub type ScriptResult = Result<i32, Box<Error>>;
@@ -21,6 +27,12 @@ pub type ScriptResult = Result<i32, Box<Error>>;
 pub type Function = fn(Runtime) -> ScriptResult;
 pub type Closure = FnMut(Runtime) -> ScriptResult;
 
+/// Returned by a Rust function to suspend the coroutine calling it.
+///
+/// The function must have deferred an operation with `Coroutines::defer()`. The coroutine is later
+/// resumed with `true` followed by the values to return, or `false` and an error message to raise.
+pub const YIELD: i32 = -1;
+
 
 /// Manages the Lua interpreter runtime for a Lua script.
 pub struct Runtime {
@@ -106,7 +118,16 @@ impl Runtime {
 
     /// Executes the script.
     pub fn load(&self) -> Result<(), Box<Error>> {
-        let path_str = if let Some(s) = self.environment.path().to_str() {
+        self.environment.mark_included(self.environment.path());
+        try!(self.load_file(self.environment.path()));
+
+        // Now that every task is known, dependencies can be resolved.
+        self.environment.qualify_dependencies()
+    }
+
+    /// Executes a script file in the current scope.
+    pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<Error>> {
+        let path_str = if let Some(s) = path.as_ref().to_str() {
             s
         } else {
             return Err("path contains invalid characters".into());
@@ -212,14 +233,41 @@ impl Runtime {
             let function: Function = mem::transmute(fn_ptr);
 
             // Invoke the function.
-            function(runtime).unwrap_or_else(|err: Box<Error>| {
+            let results = function(runtime).unwrap_or_else(|err: Box<Error>| {
                 let mut state = lua::State::from_ptr(ptr);
 
                 state.location(1);
                 state.push_string(err.description());
                 state.concat(2);
                 state.error();
-            }) as c_int
+            }) as c_int;
+
+            // Suspend the coroutine until the deferred operation finishes. The number of values
+            // already on the stack is kept so that the reply can be found when resumed.
+            if results == YIELD {
+                let top = lua::State::from_ptr(ptr).get_top();
+                return ffi::lua_yieldk(ptr, 0, top as ffi::lua_KContext, Some(continue_wrapper));
+            }
+
+            results
+        }
+
+        // Finishes a call to a Rust function once its coroutine is resumed.
+        unsafe extern fn continue_wrapper(ptr: *mut ffi::lua_State,
+                                          _: c_int,
+                                          base: ffi::lua_KContext)
+                                          -> c_int {
+            let mut state = lua::State::from_ptr(ptr);
+            let base = base as c_int;
+
+            if state.to_bool(base + 1) {
+                state.get_top() - base - 1
+            } else {
+                state.location(1);
+                state.push_value(base + 2);
+                state.concat(2);
+                state.error()
+            }
         }
     }
 
@@ -312,6 +360,53 @@ impl Runtime {
     }
 }
 
+/// Pushes a value onto the stack of a Lua state.
+pub fn push_value(state: &mut lua::State, value: &Value) {
+    match *value {
+        Value::String(ref value) => state.push_string(value),
+        Value::Number(value) => state.push_number(value),
+        Value::Boolean(value) => state.push_bool(value),
+        Value::Bytes(ref value) => state.push_bytes(value),
+    }
+}
+
+/// Creates an interrupt that stops a task busy running Lua code in a state, once it times out.
+///
+/// Setting a hook is one of the few things Lua allows from another thread. The hook raises an
+/// error at the next instruction, but only if the task running by then is one that was stopped.
+/// The state must live for as long as the interrupt is in use.
+pub fn interrupt(state: &lua::State) -> Interrupt {
+    struct StatePtr(*mut ffi::lua_State);
+    unsafe impl Send for StatePtr {}
+
+    let state = StatePtr(state.as_ptr());
+
+    Box::new(move || unsafe {
+        ffi::lua_sethook(state.0, Some(stop_hook), ffi::LUA_MASKCOUNT, 1);
+    })
+}
+
+/// Hook that raises an error in the task running in a state if it has been stopped.
+unsafe extern fn stop_hook(ptr: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    ffi::lua_sethook(ptr, None, 0, 0);
+
+    let stopped = {
+        let runtime = Runtime::from_ptr(ptr);
+        let environment = runtime.environment();
+
+        match environment.current_task() {
+            Some(task) => environment.processes().is_killed(&task),
+            None => false,
+        }
+    };
+
+    if stopped {
+        let mut state = lua::State::from_ptr(ptr);
+        state.push_string("task timed out");
+        state.error();
+    }
+}
+
 /// Implement cloning for runtime references, with the same semantics as an Rc.
 impl Clone for Runtime {
     fn clone(&self) -> Self {
@@ -0,0 +1,165 @@
+use std::iter;
+use std::path::Path;
+
+
+/// The namespace and directory of a Rotefile.
+///
+/// The root Rotefile has an empty scope. Rotefiles loaded with `include` get a scope of their own,
+/// so that their task names are prefixed with a namespace such as `api:` and their file names are
+/// relative to their own directory.
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    /// Prefix of the names of tasks defined in the scope, such as `api:`.
+    pub namespace: String,
+
+    /// Directory of the Rotefile, relative to the directory of the root Rotefile. The directory
+    /// is normalized, and may not lead outside the directory of the root Rotefile.
+    pub directory: String,
+}
+
+impl Scope {
+    /// Gets the full name of a task defined in the scope.
+    pub fn task_name(&self, name: &str) -> String {
+        format!("{}{}", self.namespace, name)
+    }
+
+    /// Converts a file path relative to the scope into a path relative to the root Rotefile.
+    pub fn file_path(&self, path: &str) -> String {
+        if self.directory.is_empty() || Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+
+        normalize(&format!("{}/{}", self.directory, path))
+    }
+
+    /// Converts a file path relative to the root Rotefile into a path relative to the scope.
+    pub fn relative_path(&self, path: &str) -> String {
+        if self.directory.is_empty() || Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+
+        if path == self.directory {
+            return ".".to_string();
+        }
+
+        let prefix = format!("{}/", self.directory);
+        if path.starts_with(&prefix) {
+            return path[prefix.len()..].to_string();
+        }
+
+        let up: String = iter::repeat("../").take(self.directory.split('/').count()).collect();
+        format!("{}{}", up, path)
+    }
+
+    /// Resolves a dependency named in the scope to a full task name or file path.
+    ///
+    /// A name starting with `:` always refers to a task in the root Rotefile. Otherwise, a task
+    /// defined in the same scope is preferred, then a task with the exact name given, such as
+    /// `web:build`, and finally a file relative to the scope directory.
+    pub fn qualify<F: Fn(&str) -> bool>(&self, name: &str, is_task: F) -> String {
+        if name.starts_with(':') {
+            return name[1..].to_string();
+        }
+
+        let local = self.task_name(name);
+        if is_task(&local) {
+            local
+        } else if name.contains(':') || is_task(name) {
+            name.to_string()
+        } else {
+            self.file_path(name)
+        }
+    }
+}
+
+/// Removes empty and `.` components from a relative path, and resolves `..` components where the
+/// path allows. Returns `.` if nothing is left.
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if parts.last().map_or(false, |last| *last != "..") => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(directory: &str) -> Scope {
+        Scope {
+            namespace: if directory.is_empty() {
+                String::new()
+            } else {
+                format!("{}:", directory)
+            },
+            directory: directory.to_string(),
+        }
+    }
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!(normalize("a/./b/../c"), "a/c");
+        assert_eq!(normalize("a//b/"), "a/b");
+        assert_eq!(normalize("../a/../../b"), "../../b");
+        assert_eq!(normalize("a/.."), ".");
+        assert_eq!(normalize(""), ".");
+    }
+
+    #[test]
+    fn file_paths_are_relative_to_the_root() {
+        assert_eq!(scope("api").file_path("src/main.c"), "api/src/main.c");
+        assert_eq!(scope("api").file_path("./out//main.o"), "api/out/main.o");
+        assert_eq!(scope("api/v2").file_path("../lib.c"), "api/lib.c");
+        assert_eq!(scope("api").file_path(".."), ".");
+        assert_eq!(scope("api").file_path("../../shared/lib.c"), "../shared/lib.c");
+        assert_eq!(scope("api").file_path("/usr/include/stdio.h"), "/usr/include/stdio.h");
+        assert_eq!(scope("").file_path("./src/main.c"), "./src/main.c");
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_the_scope() {
+        assert_eq!(scope("api").relative_path("api/out/main.o"), "out/main.o");
+        assert_eq!(scope("api").relative_path("api"), ".");
+        assert_eq!(scope("api").relative_path("apix/main.o"), "../apix/main.o");
+        assert_eq!(scope("api/v2").relative_path("lib.c"), "../../lib.c");
+        assert_eq!(scope("api").relative_path("/tmp/out"), "/tmp/out");
+        assert_eq!(scope("").relative_path("out/main.o"), "out/main.o");
+    }
+
+    #[test]
+    fn file_and_relative_paths_round_trip() {
+        let scope = scope("api/v2");
+
+        for path in &["main.o", "out/main.o", "../../lib.c"] {
+            assert_eq!(scope.relative_path(&scope.file_path(path)), normalize(path));
+        }
+    }
+
+    #[test]
+    fn dependencies_prefer_local_tasks() {
+        let tasks = ["build", "api:build", "api:test", "web:build"];
+        let is_task = |name: &str| tasks.contains(&name);
+        let api = scope("api");
+
+        assert_eq!(api.qualify("build", &is_task), "api:build");
+        assert_eq!(api.qualify(":build", &is_task), "build");
+        assert_eq!(api.qualify("web:build", &is_task), "web:build");
+        assert_eq!(api.qualify("main.c", &is_task), "api/main.c");
+        assert_eq!(scope("").qualify("api:test", &is_task), "api:test");
+        assert_eq!(scope("web").qualify("test", &is_task), "web/test");
+    }
+}
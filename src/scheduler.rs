@@ -0,0 +1,452 @@
+use graph::Graph;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::rc::Rc;
+use task::{Task, CONSOLE_POOL};
+
+
+/// Decides the order that tasks are run in.
+///
+/// The scheduler keeps a count of unfinished dependencies for each task. Once a task has no
+/// unfinished dependencies, it is added to a ready set, and any ready task can be run regardless of
+/// its position in the original schedule. Ready tasks that lie on the longest remaining path through
+/// the graph (the critical path) are handed out first, so that long chains of tasks are started as
+/// early as possible.
+///
+/// Tasks can also be limited by the pool they belong to. A task is not handed out while its pool
+/// is full, and a task in the console pool is only handed out once every other task has finished,
+/// and nothing else is handed out while it runs.
+pub struct Scheduler {
+    /// Every scheduled task, keyed by name.
+    nodes: HashMap<String, Node>,
+
+    /// Tasks that are ready to run, ordered by priority.
+    ready: BinaryHeap<Ready>,
+
+    /// Number of tasks that have not completed yet.
+    remaining: usize,
+
+    /// Number of tasks that have been handed out but have not completed yet.
+    running: usize,
+
+    /// Every pool, keyed by name.
+    pools: HashMap<String, Pool>,
+
+    /// Indicates if a task in the console pool is running.
+    exclusive: bool,
+}
+
+/// A pool limiting the number of tasks that can run at once.
+struct Pool {
+    depth: usize,
+    running: usize,
+}
+
+/// The scheduling state of a single task.
+struct Node {
+    task: Rc<Task>,
+
+    /// Number of dependencies that have not completed yet.
+    pending: usize,
+
+    /// Names of scheduled tasks that depend on this task.
+    dependents: Vec<String>,
+
+    /// Estimated time in milliseconds from the start of this task until everything that depends
+    /// on it has finished.
+    priority: u64,
+
+    /// Position of the task in the original schedule.
+    order: usize,
+
+    /// Indicates if the task will not be run because a dependency failed.
+    skipped: bool,
+}
+
+impl Scheduler {
+    /// Creates a scheduler for a list of tasks in dependency order, as produced by `Graph::solve()`.
+    ///
+    /// The depth of each pool that tasks can belong to is given by `pools`. The `estimate` function
+    /// is used to estimate how long each task will take to run in milliseconds.
+    pub fn new<F>(graph: &Graph,
+                  schedule: VecDeque<Rc<Task>>,
+                  pools: HashMap<String, usize>,
+                  estimate: F)
+                  -> Result<Scheduler, Box<Error>>
+        where F: Fn(&Task) -> u64
+    {
+        let mut nodes = HashMap::new();
+        let mut order = Vec::new();
+
+        for (index, task) in schedule.into_iter().enumerate() {
+            if let Some(pool) = task.pool() {
+                if !pools.contains_key(pool) {
+                    return Err(format!("task '{}' uses undefined pool '{}'", task.name(), pool)
+                        .into());
+                }
+            }
+
+            order.push(task.name().to_string());
+            nodes.insert(task.name().to_string(),
+                         Node {
+                             task: task,
+                             pending: 0,
+                             dependents: Vec::new(),
+                             priority: 0,
+                             order: index,
+                             skipped: false,
+                         });
+        }
+
+        // Connect each task to the scheduled tasks that it depends on. Dependencies that are not
+        // scheduled are already satisfied, and dependencies can refer to a task by any of its
+        // outputs, so the same task may be listed more than once.
+        for name in &order {
+            let mut dependencies = HashSet::new();
+
+            for dependency in nodes[name].task.dependencies() {
+                let dependency = try!(graph.get(dependency));
+
+                if nodes.contains_key(dependency.name()) {
+                    dependencies.insert(dependency.name().to_string());
+                }
+            }
+
+            nodes.get_mut(name).unwrap().pending = dependencies.len();
+            for dependency in dependencies {
+                nodes.get_mut(&dependency).unwrap().dependents.push(name.clone());
+            }
+        }
+
+        // Compute the critical path length for each task. Walking the schedule backwards visits
+        // every dependent before the tasks it depends on.
+        for name in order.iter().rev() {
+            let longest = nodes[name]
+                .dependents
+                .iter()
+                .map(|dependent| nodes[dependent].priority)
+                .max()
+                .unwrap_or(0);
+
+            let node = nodes.get_mut(name).unwrap();
+            node.priority = estimate(&*node.task) + longest;
+        }
+
+        let mut scheduler = Scheduler {
+            ready: BinaryHeap::new(),
+            remaining: nodes.len(),
+            running: 0,
+            pools: pools.into_iter()
+                .map(|(name, depth)| {
+                    (name,
+                     Pool {
+                        depth: depth,
+                        running: 0,
+                    })
+                })
+                .collect(),
+            exclusive: false,
+            nodes: nodes,
+        };
+
+        for name in order {
+            if scheduler.nodes[&name].pending == 0 {
+                scheduler.push_ready(&name);
+            }
+        }
+
+        Ok(scheduler)
+    }
+
+    /// Gets the total number of tasks scheduled.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Checks if every scheduled task has completed.
+    pub fn is_finished(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Checks if there are tasks ready to be run.
+    pub fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Takes the highest priority task that is ready to run and that its pool allows to start, if
+    /// any.
+    pub fn next(&mut self) -> Option<Rc<Task>> {
+        let mut deferred = Vec::new();
+        let mut next = None;
+
+        while let Some(ready) = self.ready.pop() {
+            if self.can_start(&*ready.task) {
+                next = Some(ready.task);
+                break;
+            }
+
+            // A console task has to wait for every running task to finish. Do not start anything
+            // else in the meantime, or it might never get to run.
+            let console = ready.task.pool() == Some(CONSOLE_POOL);
+            deferred.push(ready);
+
+            if console {
+                break;
+            }
+        }
+
+        for ready in deferred {
+            self.ready.push(ready);
+        }
+
+        if let Some(ref task) = next {
+            self.running += 1;
+
+            if let Some(pool) = task.pool() {
+                self.pools.get_mut(pool).unwrap().running += 1;
+                self.exclusive = pool == CONSOLE_POOL;
+            }
+        }
+
+        next
+    }
+
+    /// Marks a task as completed, making any tasks that were only waiting on it ready to run.
+    pub fn complete(&mut self, name: &str) {
+        self.finish(name);
+
+        let dependents = self.nodes[name].dependents.clone();
+        for dependent in dependents {
+            let pending = {
+                let node = self.nodes.get_mut(&dependent).unwrap();
+                node.pending -= 1;
+                node.pending
+            };
+
+            if pending == 0 && !self.nodes[&dependent].skipped {
+                self.push_ready(&dependent);
+            }
+        }
+    }
+
+    /// Marks a task as failed.
+    ///
+    /// Every task that depends on the failed task, directly or indirectly, is skipped and will
+    /// never become ready. Returns the names of the skipped tasks.
+    pub fn fail(&mut self, name: &str) -> Vec<String> {
+        self.finish(name);
+
+        let mut skipped = Vec::new();
+        let mut stack = self.nodes[name].dependents.clone();
+
+        while let Some(dependent) = stack.pop() {
+            let node = self.nodes.get_mut(&dependent).unwrap();
+
+            if !node.skipped {
+                node.skipped = true;
+                self.remaining -= 1;
+                stack.extend(node.dependents.iter().cloned());
+                skipped.push(dependent);
+            }
+        }
+
+        skipped
+    }
+
+    /// Checks if a task's pool allows it to start now.
+    fn can_start(&self, task: &Task) -> bool {
+        if self.exclusive {
+            return false;
+        }
+
+        match task.pool() {
+            Some(pool) if pool == CONSOLE_POOL => self.running == 0,
+            Some(pool) => self.pools[pool].running < self.pools[pool].depth,
+            None => true,
+        }
+    }
+
+    /// Releases the resources held by a task that has stopped running.
+    fn finish(&mut self, name: &str) {
+        self.remaining -= 1;
+        self.running -= 1;
+
+        if let Some(pool) = self.nodes[name].task.pool() {
+            self.pools.get_mut(pool).unwrap().running -= 1;
+
+            if pool == CONSOLE_POOL {
+                self.exclusive = false;
+            }
+        }
+    }
+
+    fn push_ready(&mut self, name: &str) {
+        let node = &self.nodes[name];
+        trace!("task '{}' is ready (priority {})", name, node.priority);
+
+        self.ready.push(Ready {
+            priority: node.priority,
+            order: node.order,
+            task: node.task.clone(),
+        });
+    }
+}
+
+/// A task in the ready set.
+struct Ready {
+    priority: u64,
+    order: usize,
+    task: Rc<Task>,
+}
+
+// Ready tasks are ordered by priority first, then by their order in the original schedule.
+impl Ord for Ready {
+    fn cmp(&self, other: &Ready) -> Ordering {
+        (self.priority, other.order).cmp(&(other.priority, self.order))
+    }
+}
+
+impl PartialOrd for Ready {
+    fn partial_cmp(&self, other: &Ready) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ready {
+    fn eq(&self, other: &Ready) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ready {}
+
+
+#[cfg(test)]
+mod tests {
+    use graph::Graph;
+    use std::collections::{HashMap, VecDeque};
+    use std::error::Error;
+    use std::rc::Rc;
+    use super::*;
+    use task::{NamedTask, Task, CONSOLE_POOL};
+
+    fn task(name: &str, dependencies: &[&str], pool: Option<&str>) -> Rc<Task> {
+        let dependencies: Vec<String> = dependencies.iter().map(|s| s.to_string()).collect();
+        let mut task = NamedTask::new(name,
+                                      None,
+                                      dependencies,
+                                      None::<fn() -> Result<(), Box<Error>>>);
+        task.pool = pool.map(|pool| pool.to_string());
+        Rc::new(task)
+    }
+
+    /// Creates a scheduler for tasks given in dependency order, where every task takes as long.
+    fn scheduler(tasks: Vec<Rc<Task>>, pools: &[(&str, usize)]) -> Scheduler {
+        let mut graph = Graph::new();
+        for task in &tasks {
+            graph.insert(task.clone());
+        }
+
+        let mut depths = HashMap::new();
+        depths.insert(CONSOLE_POOL.to_string(), 1);
+        for &(pool, depth) in pools {
+            depths.insert(pool.to_string(), depth);
+        }
+
+        Scheduler::new(&graph, tasks.into_iter().collect::<VecDeque<_>>(), depths, |_| 1)
+            .unwrap()
+    }
+
+    fn next(scheduler: &mut Scheduler) -> Option<String> {
+        scheduler.next().map(|task| task.name().to_string())
+    }
+
+    #[test]
+    fn critical_path_goes_first() {
+        let mut scheduler = scheduler(vec![task("short", &[], None),
+                                           task("a", &[], None),
+                                           task("b", &["a"], None),
+                                           task("c", &["b"], None)],
+                                      &[]);
+
+        assert_eq!(next(&mut scheduler), Some("a".to_string()));
+        assert_eq!(next(&mut scheduler), Some("short".to_string()));
+        assert_eq!(next(&mut scheduler), None);
+
+        scheduler.complete("a");
+        assert_eq!(next(&mut scheduler), Some("b".to_string()));
+    }
+
+    #[test]
+    fn ties_keep_schedule_order() {
+        let mut scheduler = scheduler(vec![task("x", &[], None), task("y", &[], None)], &[]);
+
+        assert_eq!(next(&mut scheduler), Some("x".to_string()));
+        assert_eq!(next(&mut scheduler), Some("y".to_string()));
+    }
+
+    #[test]
+    fn pools_limit_running_tasks() {
+        let mut scheduler = scheduler(vec![task("x", &[], Some("link")),
+                                           task("y", &[], Some("link")),
+                                           task("z", &[], None)],
+                                      &[("link", 1)]);
+
+        assert_eq!(next(&mut scheduler), Some("x".to_string()));
+        assert_eq!(next(&mut scheduler), Some("z".to_string()));
+        assert_eq!(next(&mut scheduler), None);
+        assert!(scheduler.has_ready());
+
+        scheduler.complete("x");
+        assert_eq!(next(&mut scheduler), Some("y".to_string()));
+    }
+
+    #[test]
+    fn console_tasks_run_alone() {
+        let mut scheduler = scheduler(vec![task("x", &[], None),
+                                           task("console", &[], Some(CONSOLE_POOL)),
+                                           task("y", &[], None)],
+                                      &[]);
+
+        // The console task waits for the running task, and holds up the task after it.
+        assert_eq!(next(&mut scheduler), Some("x".to_string()));
+        assert_eq!(next(&mut scheduler), None);
+
+        scheduler.complete("x");
+        assert_eq!(next(&mut scheduler), Some("console".to_string()));
+        assert_eq!(next(&mut scheduler), None);
+
+        scheduler.complete("console");
+        assert_eq!(next(&mut scheduler), Some("y".to_string()));
+    }
+
+    #[test]
+    fn failures_skip_dependents() {
+        let mut scheduler = scheduler(vec![task("a", &[], None),
+                                           task("b", &["a"], None),
+                                           task("c", &["b"], None),
+                                           task("d", &[], None)],
+                                      &[]);
+
+        assert_eq!(next(&mut scheduler), Some("a".to_string()));
+        assert_eq!(scheduler.fail("a"), vec!["b", "c"]);
+
+        assert_eq!(next(&mut scheduler), Some("d".to_string()));
+        assert!(!scheduler.is_finished());
+
+        scheduler.complete("d");
+        assert!(scheduler.is_finished());
+        assert_eq!(next(&mut scheduler), None);
+    }
+
+    #[test]
+    fn undefined_pools_are_rejected() {
+        let mut graph = Graph::new();
+        let task = task("x", &[], Some("missing"));
+        graph.insert(task.clone());
+
+        let schedule = vec![task].into_iter().collect();
+        assert!(Scheduler::new(&graph, schedule, HashMap::new(), |_| 1).is_err());
+    }
+}
@@ -1,8 +1,54 @@
 use std::cmp::Ordering;
 use std::error::Error;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 
+/// Name of the built-in pool for tasks that need exclusive access to the terminal.
+pub const CONSOLE_POOL: &'static str = "console";
+
+/// The kinds of tasks that can appear in a task graph.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// A task defined by name in the Rotefile.
+    Named,
+
+    /// A task created by a rule to produce a file.
+    File,
+
+    /// A source file that is not produced by any task.
+    Source,
+}
+
+impl Kind {
+    /// Gets a short name for the kind of task.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Named => "task",
+            Kind::File => "file",
+            Kind::Source => "source",
+        }
+    }
+}
+
+/// Whether a task is satisfied, and if not, why it has to run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The task is up to date and does not need to run.
+    Satisfied,
+
+    /// The task has to run for the given reason.
+    Outdated(String),
+}
+
+impl Status {
+    pub fn is_satisfied(&self) -> bool {
+        *self == Status::Satisfied
+    }
+}
+
 /// A single task that can be run.
 ///
 /// A task represents a single unit of work. Tasks are created after all rules and named tasks are
@@ -11,22 +57,214 @@ pub trait Task {
     /// Gets the synthesized name of the task.
     fn name<'a>(&'a self) -> &'a str;
 
-    /// Checks if the task is satisfied.
+    /// Gets the kind of the task.
+    fn kind(&self) -> Kind;
+
+    /// Checks if the task is satisfied, giving the reason why it has to run if it is not.
     ///
     /// A task is not satisfied when its conditions are not met and its action must be run to
     /// create the desired output and move to the satisfied state. Task implementations should make
-    /// sure that this always returns `true` after the action is run.
-    fn satisfied(&self) -> bool;
+    /// sure that this always returns `Status::Satisfied` after the action is run.
+    fn status(&self) -> Status;
+
+    /// Checks if the task is satisfied.
+    fn satisfied(&self) -> bool {
+        self.status().is_satisfied()
+    }
+
+    /// Gets the names of the files that the task produces.
+    ///
+    /// A task can be referred to by any of its outputs as well as by its name.
+    fn outputs(&self) -> &[String] {
+        &[]
+    }
 
     /// Gets an array of task names that this task depends on.
     fn dependencies(&self) -> &[String];
 
+    /// Gets the name of the pool the task belongs to, if any.
+    ///
+    /// No more tasks in a pool are run at once than the depth of the pool allows.
+    fn pool(&self) -> Option<&str> {
+        None
+    }
+
+    /// Gets how long the task is allowed to run before it is stopped, if limited.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Gets how many times the task should be run again if it fails.
+    fn retries(&self) -> u32 {
+        0
+    }
+
+    /// Gets how long to wait before running the task again after it fails.
+    fn retry_delay(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    /// Gets glob patterns of additional files that the task should be run again for in watch
+    /// mode, besides its dependencies.
+    fn watch_patterns(&self) -> &[String] {
+        &[]
+    }
+
     /// Runs the task.
     fn run(&self) -> Result<(), Box<Error>>;
+
+    /// Prepares the function of the task to be called as a coroutine, instead of running the task.
+    ///
+    /// Returns `None` if the task has no function to call this way, in which case it is run with
+    /// `run()` instead. Otherwise, `complete()` must be called with the outcome of the function
+    /// once it returns.
+    fn start(&self) -> Result<Option<PreparedCall>, Box<Error>> {
+        Ok(None)
+    }
+
+    /// Finishes running a task started with `start()`, given the outcome of its function.
+    fn complete(&self, outcome: Result<(), Box<Error>>) -> Result<(), Box<Error>> {
+        outcome
+    }
+}
+
+/// A call to the function of a task that is ready to be made.
+///
+/// The function has been pushed onto the Lua stack of the runtime, followed by its arguments, and
+/// the environment is set up for the task to run.
+#[derive(Clone, Copy, Debug)]
+pub struct PreparedCall {
+    /// The number of arguments pushed after the function.
+    pub nargs: i32,
 }
 
 type ActionFn = Fn() -> Result<(), Box<Error>>;
 
+/// Prepares the action of a task to be called as a coroutine.
+type StartFn = Fn() -> Result<PreparedCall, Box<Error>>;
+
+/// The types of values that task parameters can take.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParamType {
+    String,
+    Number,
+    Boolean,
+}
+
+impl ParamType {
+    /// Gets the name of the type.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ParamType::String => "string",
+            ParamType::Number => "number",
+            ParamType::Boolean => "boolean",
+        }
+    }
+
+    /// Converts a value given on the command line to the type.
+    pub fn parse(&self, value: &str) -> Result<Value, String> {
+        match *self {
+            ParamType::String => Ok(Value::String(value.to_string())),
+            ParamType::Number => {
+                value.parse()
+                    .map(Value::Number)
+                    .map_err(|_| format!("'{}' is not a number", value))
+            }
+            ParamType::Boolean => {
+                match value {
+                    "true" | "yes" | "on" | "1" => Ok(Value::Boolean(true)),
+                    "false" | "no" | "off" | "0" => Ok(Value::Boolean(false)),
+                    _ => Err(format!("'{}' is not a boolean", value)),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ParamType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ParamType, String> {
+        match s {
+            "string" => Ok(ParamType::String),
+            "number" => Ok(ParamType::Number),
+            "boolean" => Ok(ParamType::Boolean),
+            _ => Err(format!("unknown parameter type '{}'", s)),
+        }
+    }
+}
+
+/// A value passed to Lua, such as a task parameter or the result of a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+
+    /// A string that may not be valid UTF-8, such as the output of a command.
+    Bytes(Vec<u8>),
+}
+
+/// A named parameter that a task accepts from the command line.
+#[derive(Clone, Debug)]
+pub struct Param {
+    /// The name of the parameter.
+    pub name: String,
+
+    /// The parameter description.
+    pub description: Option<String>,
+
+    /// The type of the parameter.
+    pub kind: ParamType,
+
+    /// The value used when the parameter is not given.
+    pub default: Option<String>,
+
+    /// Indicates if the task cannot run without the parameter.
+    pub required: bool,
+}
+
+impl Param {
+    /// Describes how to give the parameter on the command line, such as `[env=<string>]`.
+    pub fn usage(&self) -> String {
+        if self.required {
+            format!("{}=<{}>", self.name, self.kind.name())
+        } else {
+            format!("[{}=<{}>]", self.name, self.kind.name())
+        }
+    }
+}
+
+/// Arguments given to a task on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct Arguments {
+    /// Arguments given by name, as `name=value`.
+    pub named: Vec<(String, String)>,
+
+    /// Arguments given by position, after `--`.
+    pub positional: Vec<String>,
+}
+
+impl Arguments {
+    pub fn is_empty(&self) -> bool {
+        self.named.is_empty() && self.positional.is_empty()
+    }
+}
+
+/// Checks if a name can be used for a named argument, as in `name=value`.
+///
+/// Names must start with a letter or underscore, followed by letters, digits, underscores, or
+/// dashes. Anything else containing `=`, such as `out/a=b.o`, is taken to be the name of a task.
+pub fn is_argument_name(name: &str) -> bool {
+    !name.is_empty() &&
+    name.bytes().enumerate().all(|(i, c)| {
+        let letter = (c >= b'a' && c <= b'z') || (c >= b'A' && c <= b'Z') || c == b'_';
+        let digit = (c >= b'0' && c <= b'9') || c == b'-';
+
+        letter || (digit && i > 0)
+    })
+}
+
 /// A single named build task.
 pub struct NamedTask {
     /// The name of the task.
@@ -38,8 +276,29 @@ pub struct NamedTask {
     /// A list of tasks that must be ran before this task.
     pub dependencies: Vec<String>,
 
+    /// The pool the task belongs to.
+    pub pool: Option<String>,
+
+    /// How long the task is allowed to run.
+    pub timeout: Option<Duration>,
+
+    /// How many times the task is run again if it fails.
+    pub retries: u32,
+
+    /// How long to wait before running the task again.
+    pub retry_delay: Duration,
+
+    /// Glob patterns of additional files to watch in watch mode.
+    pub watch: Vec<String>,
+
+    /// Parameters that the task accepts from the command line.
+    pub params: Vec<Param>,
+
     /// Rule action.
     action: Option<Box<ActionFn>>,
+
+    /// Prepares the action to be called as a coroutine.
+    start: Option<Box<StartFn>>,
 }
 
 impl NamedTask {
@@ -56,16 +315,95 @@ impl NamedTask {
             name: name.into(),
             description: description.map(|s| s.into()),
             dependencies: dependencies.into(),
+            pool: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            watch: Vec::new(),
+            params: Vec::new(),
             action: action.map(|a| Box::new(a) as Box<ActionFn>),
+            start: None,
         }
     }
 
+    /// Sets how to prepare the action of the task to be called as a coroutine.
+    pub fn set_start<F>(&mut self, start: F)
+        where F: Fn() -> Result<PreparedCall, Box<Error>> + 'static
+    {
+        self.start = Some(Box::new(start));
+    }
+
     pub fn description<'a>(&'a self) -> Option<&'a str> {
         match self.description {
             Some(ref description) => Some(description),
             None => None,
         }
     }
+
+    /// Describes how to run the task with its parameters, such as `deploy env=<string>`.
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.clone();
+
+        for param in &self.params {
+            usage.push(' ');
+            usage.push_str(&param.usage());
+        }
+
+        usage
+    }
+
+    /// Checks the arguments given to the task against its parameters, and converts them to the
+    /// declared types.
+    ///
+    /// Parameters that are not given take their default values. A task that declares no
+    /// parameters accepts any named arguments as strings.
+    pub fn bind(&self, arguments: &Arguments) -> Result<Vec<(String, Value)>, Box<Error>> {
+        self.bind_params(arguments)
+            .map_err(|e| format!("{}\nusage: rote {}", e, self.usage()).into())
+    }
+
+    fn bind_params(&self, arguments: &Arguments) -> Result<Vec<(String, Value)>, String> {
+        if self.params.is_empty() {
+            return Ok(arguments.named
+                .iter()
+                .map(|&(ref name, ref value)| (name.clone(), Value::String(value.clone())))
+                .collect());
+        }
+
+        for &(ref name, _) in &arguments.named {
+            if !self.params.iter().any(|param| param.name == *name) {
+                return Err(format!("task '{}' has no parameter '{}'", self.name, name));
+            }
+        }
+
+        let mut values = Vec::new();
+
+        for param in &self.params {
+            // The last value given wins.
+            let given = arguments.named
+                .iter()
+                .rev()
+                .find(|&&(ref name, _)| *name == param.name)
+                .map(|&(_, ref value)| value);
+
+            let value = match given.or(param.default.as_ref()) {
+                Some(value) => value,
+                None if param.required => {
+                    return Err(format!("task '{}' requires parameter '{}'", self.name, param.name));
+                }
+                None => continue,
+            };
+
+            match param.kind.parse(value) {
+                Ok(value) => values.push((param.name.clone(), value)),
+                Err(e) => {
+                    return Err(format!("invalid value for parameter '{}': {}", param.name, e));
+                }
+            }
+        }
+
+        Ok(values)
+    }
 }
 
 impl Task for NamedTask {
@@ -73,15 +411,39 @@ impl Task for NamedTask {
         &self.name
     }
 
+    fn kind(&self) -> Kind {
+        Kind::Named
+    }
+
     // Named tasks should always be run.
-    fn satisfied(&self) -> bool {
-        false
+    fn status(&self) -> Status {
+        Status::Outdated("named tasks always run".to_string())
     }
 
     fn dependencies(&self) -> &[String] {
         &self.dependencies
     }
 
+    fn pool(&self) -> Option<&str> {
+        self.pool.as_ref().map(|pool| pool.as_str())
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
+    fn watch_patterns(&self) -> &[String] {
+        &self.watch
+    }
+
     fn run(&self) -> Result<(), Box<Error>> {
         if let Some(ref action) = self.action {
             action()
@@ -89,6 +451,60 @@ impl Task for NamedTask {
             Ok(())
         }
     }
+
+    fn start(&self) -> Result<Option<PreparedCall>, Box<Error>> {
+        match self.start {
+            Some(ref start) => start().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A source file that already exists on disk and is not produced by any task or rule.
+///
+/// Source files are leaves in the task graph; they are always satisfied as long as the file
+/// exists.
+pub struct SourceFile {
+    /// Path of the file.
+    path: String,
+}
+
+impl SourceFile {
+    pub fn new<S: Into<String>>(path: S) -> SourceFile {
+        SourceFile {
+            path: path.into(),
+        }
+    }
+}
+
+impl Task for SourceFile {
+    fn name<'a>(&'a self) -> &'a str {
+        &self.path
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Source
+    }
+
+    fn status(&self) -> Status {
+        if Path::new(&self.path).exists() {
+            Status::Satisfied
+        } else {
+            Status::Outdated(format!("source file '{}' does not exist", self.path))
+        }
+    }
+
+    fn dependencies(&self) -> &[String] {
+        &[]
+    }
+
+    // There is nothing to do to produce a source file, so it is an error if it has gone missing.
+    fn run(&self) -> Result<(), Box<Error>> {
+        match self.status() {
+            Status::Satisfied => Ok(()),
+            Status::Outdated(reason) => Err(reason.into()),
+        }
+    }
 }
 
 // Implement ordering and comparison for all task types.
@@ -119,3 +535,28 @@ impl Hash for Task {
         self.name().hash(state)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argument_names() {
+        assert!(is_argument_name("env"));
+        assert!(is_argument_name("_dry-run2"));
+        assert!(!is_argument_name(""));
+        assert!(!is_argument_name("2fast"));
+        assert!(!is_argument_name("out/a"));
+        assert!(!is_argument_name("-x"));
+    }
+
+    #[test]
+    fn params_parse_values_by_type() {
+        assert_eq!(ParamType::Number.parse("1.5"), Ok(Value::Number(1.5)));
+        assert_eq!(ParamType::Boolean.parse("yes"), Ok(Value::Boolean(true)));
+        assert_eq!(ParamType::String.parse("x"), Ok(Value::String("x".to_string())));
+        assert!(ParamType::Number.parse("ten").is_err());
+        assert!(ParamType::Boolean.parse("maybe").is_err());
+    }
+}
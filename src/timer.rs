@@ -0,0 +1,197 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// A callback scheduled to run on the timer thread.
+pub type Callback = Box<FnMut() + Send>;
+
+/// Runs callbacks after a delay, all on a single background thread.
+///
+/// The thread is started when the first callback is scheduled, and sleeps until the earliest
+/// pending callback is due. Callbacks that are cancelled before they are due are never run, and
+/// do not keep the thread busy.
+pub struct Timer {
+    shared: Arc<Shared>,
+}
+
+/// State shared between the timer and its thread.
+struct Shared {
+    state: Mutex<State>,
+
+    /// Signalled when a callback is scheduled that may be due before the others, or when the
+    /// timer is dropped.
+    changed: Condvar,
+}
+
+struct State {
+    /// When each pending callback is due, with the earliest first.
+    queue: BinaryHeap<Entry>,
+
+    /// Callbacks that have not run or been cancelled yet, keyed by ID.
+    callbacks: HashMap<usize, Callback>,
+
+    /// The ID to give to the next callback.
+    next_id: usize,
+
+    /// Indicates if the thread has been started.
+    started: bool,
+
+    /// Set once the timer has been dropped, to stop the thread.
+    stopped: bool,
+}
+
+/// A pending callback in the queue.
+#[derive(Eq, PartialEq)]
+struct Entry {
+    due: Instant,
+    id: usize,
+}
+
+impl Ord for Entry {
+    /// Orders entries in reverse, so that the heap keeps the earliest entry on top.
+    fn cmp(&self, other: &Entry) -> Ordering {
+        match other.due.cmp(&self.due) {
+            Ordering::Equal => other.id.cmp(&self.id),
+            ordering => ordering,
+        }
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    queue: BinaryHeap::new(),
+                    callbacks: HashMap::new(),
+                    next_id: 0,
+                    started: false,
+                    stopped: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Schedules a callback to run once the delay has passed. Returns an ID that can be used to
+    /// cancel the callback.
+    pub fn schedule<F>(&self, delay: Duration, callback: F) -> usize
+        where F: FnOnce() + Send + 'static
+    {
+        let mut callback = Some(callback);
+        let callback: Callback = Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        });
+
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        state.queue.push(Entry {
+            due: Instant::now() + delay,
+            id: id,
+        });
+        state.callbacks.insert(id, callback);
+
+        if !state.started {
+            state.started = true;
+            let shared = self.shared.clone();
+            thread::spawn(move || run(shared));
+        }
+
+        self.shared.changed.notify_one();
+        id
+    }
+
+    /// Cancels a callback. Returns false if the callback has already run or been cancelled.
+    pub fn cancel(&self, id: usize) -> bool {
+        // The entry stays in the queue, and is skipped once it is due.
+        self.shared.state.lock().unwrap().callbacks.remove(&id).is_some()
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+/// Runs callbacks as they become due until the timer is dropped.
+fn run(shared: Arc<Shared>) {
+    let mut state = shared.state.lock().unwrap();
+
+    while !state.stopped {
+        let now = Instant::now();
+
+        let due = match state.queue.peek() {
+            Some(entry) => entry.due,
+            None => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+
+        if due > now {
+            state = shared.changed.wait_timeout(state, due - now).unwrap().0;
+            continue;
+        }
+
+        let id = state.queue.pop().unwrap().id;
+        if let Some(mut callback) = state.callbacks.remove(&id) {
+            // Callbacks may use the timer themselves.
+            drop(state);
+            callback();
+            state = shared.state.lock().unwrap();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn callbacks_run_in_order_of_delay() {
+        let timer = Timer::new();
+        let (sender, receiver) = mpsc::channel();
+
+        for &(name, delay) in &[("slow", 60), ("fast", 10), ("medium", 30)] {
+            let sender = sender.clone();
+            timer.schedule(Duration::from_millis(delay), move || sender.send(name).unwrap());
+        }
+
+        let order: Vec<_> = receiver.iter().take(3).collect();
+        assert_eq!(order, vec!["fast", "medium", "slow"]);
+    }
+
+    #[test]
+    fn cancelled_callbacks_never_run() {
+        let timer = Timer::new();
+        let (sender, receiver) = mpsc::channel();
+
+        let cancelled = {
+            let sender = sender.clone();
+            timer.schedule(Duration::from_millis(10), move || sender.send("cancelled").unwrap())
+        };
+        timer.schedule(Duration::from_millis(30), move || sender.send("kept").unwrap());
+
+        assert!(timer.cancel(cancelled));
+        assert!(!timer.cancel(cancelled));
+        assert_eq!(receiver.recv().unwrap(), "kept");
+    }
+}
@@ -0,0 +1,124 @@
+use json::{self, JsonValue};
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+/// Thread ID used for events recorded by the main thread.
+pub const MAIN_THREAD: usize = 0;
+
+/// Records a timeline of a build in the Chrome Trace Event format.
+///
+/// The resulting file can be loaded into `chrome://tracing` or Perfetto. Every copy of a trace
+/// shares the same events, but records them for its own thread, so each worker thread gets a copy
+/// of its own from `for_thread()`.
+///
+/// See <https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU>.
+#[derive(Clone)]
+pub struct Trace {
+    /// Time that the trace started. All timestamps are relative to this.
+    start: Instant,
+
+    /// Thread that events are recorded for.
+    thread: usize,
+
+    /// Events recorded so far.
+    events: Arc<Mutex<Vec<JsonValue>>>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        let trace = Trace {
+            start: Instant::now(),
+            thread: MAIN_THREAD,
+            events: Arc::new(Mutex::new(Vec::new())),
+        };
+        trace.name_thread("main");
+
+        trace
+    }
+
+    /// Gets a copy of the trace that records events for a different thread.
+    pub fn for_thread(&self, thread: usize) -> Trace {
+        let trace = Trace {
+            start: self.start,
+            thread: thread,
+            events: self.events.clone(),
+        };
+        trace.name_thread(&format!("worker {}", thread));
+
+        trace
+    }
+
+    /// Records something that took place between two points in time.
+    pub fn span<S: Into<String>>(&self,
+                                 name: S,
+                                 category: &str,
+                                 start: Instant,
+                                 end: Instant,
+                                 args: JsonValue) {
+        let mut event = self.event(name.into(), category, "X", args);
+        event["ts"] = micros(start.duration_since(self.start)).into();
+        event["dur"] = micros(end.duration_since(start)).into();
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Records something that happened at the current point in time.
+    pub fn instant<S: Into<String>>(&self, name: S, category: &str, args: JsonValue) {
+        let mut event = self.event(name.into(), category, "i", args);
+        event["ts"] = micros(self.start.elapsed()).into();
+        event["s"] = "t".into();
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Writes every event recorded so far to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<Error>> {
+        let mut events = JsonValue::new_array();
+        for event in self.events.lock().unwrap().iter() {
+            try!(events.push(event.clone()));
+        }
+
+        let mut value = JsonValue::new_object();
+        value["traceEvents"] = events;
+        value["displayTimeUnit"] = "ms".into();
+
+        let mut file = try!(File::create(path.as_ref()));
+        try!(file.write_all(json::stringify(value).as_bytes()));
+
+        debug!("saved trace to '{}'", path.as_ref().to_string_lossy());
+        Ok(())
+    }
+
+    /// Gives the current thread a name to display in the timeline.
+    fn name_thread(&self, name: &str) {
+        let mut args = JsonValue::new_object();
+        args["name"] = name.into();
+
+        let event = self.event("thread_name".to_string(), "", "M", args);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn event(&self, name: String, category: &str, phase: &str, args: JsonValue) -> JsonValue {
+        let mut event = JsonValue::new_object();
+        event["name"] = name.into();
+        if !category.is_empty() {
+            event["cat"] = category.into();
+        }
+        event["ph"] = phase.into();
+        event["pid"] = 1f64.into();
+        event["tid"] = (self.thread as f64).into();
+        if !args.is_null() {
+            event["args"] = args;
+        }
+
+        event
+    }
+}
+
+/// Converts a duration to microseconds, the unit used for timestamps in trace files.
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000000.0 + duration.subsec_nanos() as f64 / 1000.0
+}
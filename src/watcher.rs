@@ -0,0 +1,328 @@
+use glob;
+use processes;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+
+/// How often to check files for changes when file system notifications are not available.
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// How long files must stay unchanged before a burst of changes is considered finished.
+const DEBOUNCE_MS: u64 = 200;
+
+
+/// The modification time of every watched file, or `None` for files that do not exist.
+pub type Snapshot = HashMap<String, Option<SystemTime>>;
+
+/// Watches a set of files for changes.
+///
+/// On Linux, inotify is used to find out when something might have changed. Everywhere else, the
+/// files are polled periodically. Either way, the modification times of the files are compared to
+/// find out exactly which files changed.
+pub struct Watcher {
+    /// Paths of individual files to watch.
+    paths: HashSet<String>,
+
+    /// Glob patterns matching additional files to watch.
+    patterns: Vec<String>,
+
+    /// Paths of watched files that are produced by tasks.
+    outputs: HashSet<String>,
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        Watcher {
+            paths: HashSet::new(),
+            patterns: Vec::new(),
+            outputs: HashSet::new(),
+        }
+    }
+
+    /// Adds a file to watch.
+    pub fn add_path<S: Into<String>>(&mut self, path: S) {
+        self.paths.insert(path.into());
+    }
+
+    /// Adds a file that is produced by tasks to watch.
+    ///
+    /// Unlike other files, changes that tasks make to their outputs while they run are expected,
+    /// and are not reported.
+    pub fn add_output<S: Into<String>>(&mut self, path: S) {
+        let path = path.into();
+        self.paths.insert(path.clone());
+        self.outputs.insert(path);
+    }
+
+    /// Adds a glob pattern matching files to watch. Files that match the pattern later on are
+    /// watched as well.
+    pub fn add_pattern<S: Into<String>>(&mut self, pattern: S) -> Result<(), Box<Error>> {
+        let pattern = pattern.into();
+        try!(glob::Pattern::new(&pattern));
+        self.patterns.push(pattern);
+
+        Ok(())
+    }
+
+    /// Gets the number of files currently being watched.
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    /// Blocks until any of the watched files change, and returns the paths of the files that
+    /// changed.
+    ///
+    /// Files are compared to a snapshot taken before the tasks ran, so that files edited while
+    /// the tasks were running are reported right away. Outputs are compared to their current
+    /// state instead, since the tasks changed them.
+    ///
+    /// Changes are collected until the files have stopped changing for a short while, so that a
+    /// burst of changes, such as when switching branches, is reported all at once. Returns `None`
+    /// if the program is interrupted while waiting.
+    pub fn wait(&self, before: &Snapshot) -> Option<HashSet<String>> {
+        let notifier = inotify::Inotify::new(&self.directories());
+        if notifier.is_none() {
+            debug!("file system notifications not available; polling for changes");
+        }
+
+        let mut after = self.snapshot();
+        let mut before = before.clone();
+        for path in &self.outputs {
+            before.insert(path.clone(), after.get(path).cloned().unwrap_or(None));
+        }
+
+        while after == before {
+            if processes::interrupted() {
+                return None;
+            }
+
+            match notifier {
+                Some(ref notifier) => {
+                    if !notifier.wait(Duration::from_millis(100)) {
+                        continue;
+                    }
+                }
+                None => thread::sleep(Duration::from_millis(POLL_INTERVAL_MS)),
+            }
+
+            after = self.snapshot();
+        }
+
+        // Wait for the changes to settle.
+        loop {
+            thread::sleep(Duration::from_millis(DEBOUNCE_MS));
+            if processes::interrupted() {
+                return None;
+            }
+
+            let next = self.snapshot();
+            if next == after {
+                break;
+            }
+            after = next;
+        }
+
+        Some(before.keys()
+            .chain(after.keys())
+            .filter(|path| before.get(*path) != after.get(*path))
+            .cloned()
+            .collect())
+    }
+
+    /// Records the modification time of every watched file.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+
+        for path in &self.paths {
+            snapshot.insert(path.clone(), modified(path));
+        }
+
+        for pattern in &self.patterns {
+            if let Ok(paths) = glob::glob(pattern) {
+                for path in paths.filter_map(|path| path.ok()) {
+                    let path = path.to_string_lossy().into_owned();
+                    let time = modified(&path);
+                    snapshot.insert(path, time);
+                }
+            }
+        }
+
+        snapshot
+    }
+
+    /// Gets the directories containing the watched files.
+    ///
+    /// Directories are watched instead of the files themselves, so that files that are replaced
+    /// or created are noticed too.
+    fn directories(&self) -> HashSet<PathBuf> {
+        let mut directories = HashSet::new();
+
+        for path in self.snapshot().keys() {
+            directories.insert(parent(Path::new(path)));
+        }
+
+        // Also watch the deepest directory in each pattern without any wildcards, so that new
+        // matching files are noticed.
+        for pattern in &self.patterns {
+            let end = pattern.find(|c| c == '*' || c == '?' || c == '[').unwrap_or(pattern.len());
+            directories.insert(parent(Path::new(&pattern[..end])));
+        }
+
+        directories.into_iter().filter(|directory| directory.is_dir()).collect()
+    }
+}
+
+/// Gets the modification time of a file, if it exists.
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Gets the directory containing a path, using the current directory for relative file names.
+fn parent(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use libc::{self, c_int};
+    use std::collections::HashSet;
+    use std::ffi::CString;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// An inotify instance watching a set of directories.
+    pub struct Inotify {
+        fd: c_int,
+    }
+
+    impl Inotify {
+        /// Starts watching a set of directories. Returns `None` if inotify is not available.
+        pub fn new(directories: &HashSet<PathBuf>) -> Option<Inotify> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return None;
+            }
+
+            let inotify = Inotify { fd: fd };
+            let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE |
+                       libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_CREATE |
+                       libc::IN_DELETE;
+
+            for directory in directories {
+                let path = match CString::new(directory.to_string_lossy().into_owned()) {
+                    Ok(path) => path,
+                    Err(_) => continue,
+                };
+
+                if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+                    // Most likely, the limit on the number of watches has been reached.
+                    warn!("failed to watch directory '{}'", directory.to_string_lossy());
+                    return None;
+                }
+            }
+
+            Some(inotify)
+        }
+
+        /// Waits for events for up to the given amount of time. Returns true if any events were
+        /// received.
+        pub fn wait(&self, timeout: Duration) -> bool {
+            let mut fds = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            let timeout = timeout.as_secs() as c_int * 1000 +
+                          timeout.subsec_nanos() as c_int / 1000000;
+            if unsafe { libc::poll(&mut fds, 1, timeout) } <= 0 {
+                return false;
+            }
+
+            // Discard the events; the caller compares modification times to find out what
+            // actually changed.
+            let mut buffer = [0u8; 4096];
+            while unsafe {
+                libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+            } > 0 {}
+
+            true
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod inotify {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Placeholder for platforms without inotify.
+    pub struct Inotify;
+
+    impl Inotify {
+        pub fn new(_: &HashSet<PathBuf>) -> Option<Inotify> {
+            None
+        }
+
+        pub fn wait(&self, _: Duration) -> bool {
+            false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    fn write(path: &str, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn changes_made_during_a_run_are_reported() {
+        let directory = env::temp_dir().join("rote-test-watcher");
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("main.c").to_string_lossy().into_owned();
+        let output = directory.join("main.o").to_string_lossy().into_owned();
+        write(&source, "int main;");
+        write(&output, "old");
+
+        let mut watcher = Watcher::new();
+        watcher.add_path(source.clone());
+        watcher.add_output(output.clone());
+        let before = watcher.snapshot();
+
+        // The source is edited while the output is being rebuilt.
+        thread::sleep(Duration::from_millis(20));
+        write(&source, "int main();");
+        write(&output, "new");
+
+        let changed = watcher.wait(&before).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(changed.len(), 1);
+        assert!(changed.contains(&source));
+    }
+}
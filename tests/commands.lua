@@ -0,0 +1,20 @@
+-- Outputs are rebuilt when a variable expanded in one of their commands changes.
+local dir = "tests/fixtures/commands"
+local output = dir .. "/out.txt"
+
+os.remove(output)
+
+local function build(flags)
+    exec("env", "FLAGS=" .. flags, rote.current_exe(), "-C", dir)
+end
+
+build("first")
+build("first")
+build("second")
+
+local file = io.open(output)
+local contents = file:read("a")
+file:close()
+os.remove(output)
+
+assert(contents == "first\nsecond\n")
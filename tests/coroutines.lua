@@ -0,0 +1,5 @@
+-- Run the fixture as coroutines, so that its tasks take turns on one thread.
+exec(rote.current_exe(), "-C", "tests/fixtures/coroutines", "--coroutines", "-j2")
+
+-- Arguments given on the command line reach tasks run as coroutines.
+exec(rote.current_exe(), "-C", "tests/fixtures/coroutines", "--coroutines", "deploy", "env=staging")
@@ -0,0 +1,4 @@
+-- A module loaded from a file by the Rotefile.
+return {
+    count = 0,
+}
@@ -0,0 +1,2 @@
+-- Run the fixture with two worker threads, so that its tasks are defined from a snapshot.
+exec(rote.current_exe(), "-C", "tests/fixtures/snapshot", "-j2")